@@ -0,0 +1,566 @@
+//! TUI for browsing the synced collection (see `collection sync`). Structured
+//! like the tagger: `main` -> `run` -> loop{`draw` -> `render` components...}
+
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::io::stdout;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+use crossterm::event;
+use crossterm::event::Event;
+use crossterm::event::KeyCode;
+use crossterm::event::KeyEventKind;
+use crossterm::terminal::disable_raw_mode;
+use crossterm::terminal::enable_raw_mode;
+use crossterm::terminal::EnterAlternateScreen;
+use crossterm::terminal::LeaveAlternateScreen;
+use crossterm::ExecutableCommand;
+use itertools::Itertools;
+use ratatui::prelude::*;
+use ratatui::widgets::*;
+
+use crate::collection::Collection;
+use crate::collection::CollectionResult;
+use crate::config::CONFIG;
+use crate::io::LibraryDB;
+use crate::io::LIBRARY_ROOT;
+use crate::query::Filter;
+use crate::reconcile;
+use crate::release::Release;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    DateAdded,
+    Artist,
+    Year,
+    Rating,
+}
+
+impl SortKey {
+    fn next(&self) -> Self {
+        match self {
+            SortKey::DateAdded => SortKey::Artist,
+            SortKey::Artist => SortKey::Year,
+            SortKey::Year => SortKey::Rating,
+            SortKey::Rating => SortKey::DateAdded,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            SortKey::DateAdded => "date added",
+            SortKey::Artist => "artist",
+            SortKey::Year => "year",
+            SortKey::Rating => "rating",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Normal,
+    Search,
+}
+
+/// Case-insensitive subsequence match; consecutive characters score higher.
+/// None if `query` does not match at all.
+fn fuzzy_score(
+    query: &str,
+    text: &str,
+) -> Option<usize> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut prev: Option<usize> = None;
+    for q in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let i = pos + text[pos..].iter().position(|c| *c == q)?;
+        score += match prev {
+            Some(p) if p + 1 == i => 2,
+            _ => 1,
+        };
+        prev = Some(i);
+        pos = i + 1;
+    }
+    Some(score)
+}
+
+/// None -> first -> ... -> last -> None
+fn cycle<T: Clone + PartialEq>(
+    values: &[T],
+    current: &Option<T>,
+) -> Option<T> {
+    match current {
+        None => values.first().cloned(),
+        Some(c) => {
+            let i = values.iter().position(|v| v == c)?;
+            values.get(i + 1).cloned()
+        }
+    }
+}
+
+fn artist(r: &CollectionResult) -> String {
+    r.basic_information
+        .artists
+        .iter()
+        .map(|a| a.name.as_str())
+        .join(", ")
+}
+
+pub struct BrowserApp {
+    releases: Vec<CollectionResult>,
+    /// Indices into `releases`, after filtering and sorting
+    visible: Vec<usize>,
+    state: ListState,
+
+    mode: Mode,
+    search: String,
+    sort: SortKey,
+    filter: Filter,
+    genres: Vec<String>,
+    styles: Vec<String>,
+    folders: Vec<usize>,
+
+    /// Full releases, fetched on demand (by release ID)
+    details: HashMap<usize, Release>,
+    /// Library path (relative to the root) by instance ID
+    library: HashMap<usize, String>,
+    status: String,
+}
+
+impl BrowserApp {
+    pub fn new(
+        releases: Vec<CollectionResult>,
+        library: HashMap<usize, String>,
+    ) -> Self {
+        let distinct = |f: &dyn Fn(&CollectionResult) -> Vec<String>| {
+            releases.iter().flat_map(f).unique().sorted().collect()
+        };
+        let genres = distinct(&|r| r.basic_information.genres.clone());
+        let styles = distinct(&|r| r.basic_information.styles.clone());
+        let folders = releases
+            .iter()
+            .map(|r| r.folder_id)
+            .unique()
+            .sorted()
+            .collect();
+
+        let mut app = Self {
+            releases,
+            visible: vec![],
+            state: ListState::default(),
+            mode: Mode::Normal,
+            search: String::new(),
+            sort: SortKey::DateAdded,
+            filter: Filter::default(),
+            genres,
+            styles,
+            folders,
+            details: HashMap::new(),
+            library,
+            status: String::new(),
+        };
+        app.refresh();
+        app
+    }
+
+    /// `main` -> `run` -> loop{`draw` -> `render` -> `render` components...}
+    pub fn main(&mut self) -> io::Result<()> {
+        enable_raw_mode()?;
+        stdout().execute(EnterAlternateScreen)?;
+
+        let terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+        self.run(terminal)?;
+
+        disable_raw_mode()?;
+        stdout().execute(LeaveAlternateScreen)?;
+        Ok(())
+    }
+
+    fn run(
+        &mut self,
+        mut terminal: Terminal<impl Backend>,
+    ) -> io::Result<()> {
+        loop {
+            terminal.draw(|f| f.render_widget(&mut *self, f.size()))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            use KeyCode::*;
+            match (self.mode, key.code) {
+                (Mode::Search, Esc) => {
+                    self.search.clear();
+                    self.mode = Mode::Normal;
+                    self.refresh();
+                }
+                (Mode::Search, Enter) => self.mode = Mode::Normal,
+                (Mode::Search, Backspace) => {
+                    self.search.pop();
+                    self.refresh();
+                }
+                (Mode::Search, Char(c)) => {
+                    self.search.push(c);
+                    self.refresh();
+                }
+                (Mode::Search, _) => {}
+
+                (Mode::Normal, Char('q') | Esc) => return Ok(()),
+
+                (Mode::Normal, Char('J') | PageDown) => self.next(5),
+                (Mode::Normal, Char('K') | PageUp) => self.previous(5),
+                (Mode::Normal, Char('j') | Down) => self.next(1),
+                (Mode::Normal, Char('k') | Up) => self.previous(1),
+
+                (Mode::Normal, Char('/')) => self.mode = Mode::Search,
+                (Mode::Normal, Char('s')) => {
+                    self.sort = self.sort.next();
+                    self.refresh();
+                }
+                (Mode::Normal, Char('g')) => {
+                    self.filter.genre = cycle(&self.genres, &self.filter.genre);
+                    self.refresh();
+                }
+                (Mode::Normal, Char('t')) => {
+                    self.filter.style = cycle(&self.styles, &self.filter.style);
+                    self.refresh();
+                }
+                (Mode::Normal, Char('f')) => {
+                    self.filter.folder = cycle(&self.folders, &self.filter.folder);
+                    self.refresh();
+                }
+                (Mode::Normal, Char('c')) => {
+                    self.filter = Filter::default();
+                    self.search.clear();
+                    self.refresh();
+                }
+
+                (Mode::Normal, Enter) => self.fetch_details(),
+                (Mode::Normal, Char('o')) => self.open(),
+                (Mode::Normal, Char('L')) => {
+                    self.shell()?;
+                    terminal.clear()?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // state management
+
+    fn selected(&self) -> Option<&CollectionResult> {
+        let i = *self.visible.get(self.state.selected()?)?;
+        self.releases.get(i)
+    }
+
+    /// Reapply search, filters and sort; the selection is reset to the top.
+    fn refresh(&mut self) {
+        let releases = &self.releases;
+        let mut visible: Vec<usize> = (0..releases.len())
+            .filter(|i| self.filter.matches(&releases[*i]))
+            .collect();
+
+        match self.sort {
+            // `from_sql` is newest first
+            SortKey::DateAdded => {}
+            SortKey::Artist => visible.sort_by_cached_key(|i| artist(&releases[*i]).to_lowercase()),
+            SortKey::Year => visible.sort_by_key(|i| releases[*i].basic_information.year),
+            SortKey::Rating => visible.sort_by_key(|i| std::cmp::Reverse(releases[*i].rating)),
+        }
+
+        if !self.search.is_empty() {
+            let mut scored: Vec<(usize, usize)> = visible
+                .into_iter()
+                .filter_map(|i| {
+                    let r = &releases[i];
+                    let text = format!("{} {}", artist(r), r.basic_information.title);
+                    Some((i, fuzzy_score(&self.search, &text)?))
+                })
+                .collect();
+            // stable, so ties keep the sort order
+            scored.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+            visible = scored.into_iter().map(|(i, _)| i).collect();
+        }
+
+        self.visible = visible;
+        self.state.select((!self.visible.is_empty()).then_some(0));
+    }
+
+    /// Allows wrap-around
+    fn next(
+        &mut self,
+        step: usize,
+    ) {
+        let len = self.visible.len();
+        if let Some(curr) = self.state.selected() {
+            self.state.select(Some((curr + step) % len));
+        }
+    }
+
+    fn previous(
+        &mut self,
+        step: usize,
+    ) {
+        let len = self.visible.len();
+        if let Some(curr) = self.state.selected() {
+            self.state.select(Some((curr + len - step % len) % len));
+        }
+    }
+
+    fn fetch_details(&mut self) {
+        let Some(id) = self.selected().map(|r| r.id) else {
+            return;
+        };
+        if self.details.contains_key(&id) {
+            return;
+        }
+        match Release::get(id) {
+            Some(rel) => {
+                self.details.insert(id, rel);
+            }
+            None => self.status = format!("could not fetch release {id}"),
+        }
+    }
+
+    fn open(&mut self) {
+        let Some(id) = self.selected().map(|r| r.id) else {
+            return;
+        };
+        let url = match self.details.get(&id) {
+            Some(rel) => rel.uri.clone(),
+            None => format!("https://www.discogs.com/release/{id}"),
+        };
+        if let Err(e) = Command::new("xdg-open")
+            .arg(&url)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            self.status = format!("could not open {url}: {e}");
+        }
+    }
+
+    /// Suspend the TUI and start a shell in the library directory of the
+    /// selected release; the browser resumes when the shell exits.
+    fn shell(&mut self) -> io::Result<()> {
+        let Some(path) = self
+            .selected()
+            .and_then(|r| self.library.get(&r.instance_id))
+        else {
+            self.status = "not in library".to_string();
+            return Ok(());
+        };
+        let dir = Path::new(LIBRARY_ROOT.as_str()).join(path);
+
+        disable_raw_mode()?;
+        stdout().execute(LeaveAlternateScreen)?;
+        let result = Command::new(env::var("SHELL").unwrap_or("sh".to_string()))
+            .current_dir(&dir)
+            .status();
+        stdout().execute(EnterAlternateScreen)?;
+        enable_raw_mode()?;
+
+        if let Err(e) = result {
+            self.status = format!("could not start shell in {}: {e}", dir.display());
+        }
+        Ok(())
+    }
+}
+
+// rendering
+
+impl Widget for &mut BrowserApp {
+    fn render(
+        self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) where
+        Self: Sized,
+    {
+        let hsplit = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ]);
+        let [header, main, footer] = hsplit.areas(area);
+
+        let vsplit = Layout::horizontal(Constraint::from_percentages([49, 2, 49]));
+        let [left, _, right] = vsplit.areas(main);
+
+        self.render_header(header, buf);
+        self.render_list(left, buf);
+        self.render_details(right, buf);
+        self.render_footer(footer, buf);
+    }
+}
+
+impl BrowserApp {
+    pub fn render_header(
+        &mut self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let mut parts = vec![
+            format!("{}/{}", self.visible.len(), self.releases.len()),
+            format!("sort: {}", self.sort.as_str()),
+        ];
+        if let Some(g) = &self.filter.genre {
+            parts.push(format!("genre: {g}"));
+        }
+        if let Some(s) = &self.filter.style {
+            parts.push(format!("style: {s}"));
+        }
+        if let Some(f) = &self.filter.folder {
+            parts.push(format!("folder: {f}"));
+        }
+        if self.mode == Mode::Search || !self.search.is_empty() {
+            parts.push(format!("/{}", self.search));
+        }
+        Widget::render(Paragraph::new(parts.join(" | ")).bold(), area, buf);
+    }
+
+    pub fn render_list(
+        &mut self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let items: Vec<ListItem> = self
+            .visible
+            .iter()
+            .map(|i| {
+                let r = &self.releases[*i];
+                let line = format!(
+                    "{} - {} ({})",
+                    artist(r),
+                    r.basic_information.title,
+                    r.basic_information.year
+                );
+                match self.library.contains_key(&r.instance_id) {
+                    true => ListItem::new(line),
+                    false => ListItem::new(line).dim(),
+                }
+            })
+            .collect();
+        StatefulWidget::render(
+            List::new(items).highlight_symbol("> "),
+            area,
+            buf,
+            &mut self.state,
+        );
+    }
+
+    pub fn render_details(
+        &mut self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let block = Block::default().borders(Borders::LEFT);
+        let Some(r) = self.selected() else {
+            Widget::render(Paragraph::new("no releases").block(block), area, buf);
+            return;
+        };
+        let info = &r.basic_information;
+
+        let mut lines = vec![
+            Line::from(artist(r)).bold(),
+            Line::from(info.title.clone()).bold(),
+            Line::from(format!("{} | release {}", info.year, r.id)),
+        ];
+        for l in &info.labels {
+            lines.push(Line::from(format!("{} – {}", l.name, l.catno)));
+        }
+        for f in &info.formats {
+            lines.push(Line::from(format!(
+                "{} x {}, {}",
+                f.qty,
+                f.name,
+                f.descriptions.join(", ")
+            )));
+        }
+        lines.push(Line::from(info.genres.join(", ")));
+        lines.push(Line::from(info.styles.join(", ")).dim());
+        lines.push(Line::from(format!(
+            "rating {} | folder {} | added {}",
+            r.rating, r.folder_id, r.date_added
+        )));
+        lines.push(Line::from(match self.library.get(&r.instance_id) {
+            Some(path) => format!("library: {path}"),
+            None => "not in library".to_string(),
+        }));
+        lines.push(Line::default());
+
+        match self.details.get(&r.id) {
+            Some(rel) => {
+                if let Some(country) = &rel.country {
+                    lines.push(Line::from(format!("country: {country}")));
+                }
+                lines.extend(rel.tracklist().iter().map(|t| Line::from(t.to_string())));
+                if let Some(notes) = &rel.notes {
+                    lines.push(Line::default());
+                    lines.extend(notes.lines().map(|l| Line::from(l.to_string()).dim()));
+                }
+            }
+            None => lines.push(Line::from("<enter> to fetch tracklist").dim()),
+        }
+
+        Widget::render(
+            Paragraph::new(lines)
+                .block(block)
+                .wrap(Wrap { trim: false }),
+            area,
+            buf,
+        );
+    }
+
+    pub fn render_footer(
+        &mut self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let text = match (self.mode, self.status.is_empty()) {
+            (Mode::Search, _) => "type to search | <enter> done | <esc> clear".to_string(),
+            (Mode::Normal, false) => self.status.clone(),
+            (Mode::Normal, true) => "j/k move | / search | s sort | g genre | t style | f folder | c clear | <enter> details | o open | L library | q quit".to_string(),
+        };
+        Widget::render(Paragraph::new(text).dim(), area, buf);
+    }
+}
+
+pub fn main() {
+    let coll = Collection::from_sql(&CONFIG.db_path("collection.db")).unwrap();
+    let db = LibraryDB::load(&CONFIG.db_path("library.db")).unwrap();
+    let library = reconcile::reconcile(&coll.releases, &db.entries, &db.release_ids().unwrap())
+        .pairs
+        .into_iter()
+        .collect();
+    BrowserApp::new(coll.releases, library).main().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::browser::cycle;
+    use crate::browser::fuzzy_score;
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert!(fuzzy_score("mdkob", "Miles Davis Kind of Blue").is_some());
+        assert!(fuzzy_score("blue kind", "Miles Davis Kind of Blue").is_none());
+        assert!(fuzzy_score("kind", "Kind of Blue") > fuzzy_score("kind", "K i n d"));
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+    }
+
+    #[test]
+    fn test_cycle() {
+        let values = [1, 2];
+        assert_eq!(cycle(&values, &None), Some(1));
+        assert_eq!(cycle(&values, &Some(1)), Some(2));
+        assert_eq!(cycle(&values, &Some(2)), None);
+        assert_eq!(cycle::<usize>(&[], &None), None);
+    }
+}
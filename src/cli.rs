@@ -1,16 +1,36 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 use clap::Subcommand;
 
+use crate::config::CONFIG;
+use crate::query::Filter;
+use crate::reconcile::OutputFormat;
+use crate::transcode::ArchiveMode;
+use crate::transcode::Target;
+
+// collection browse [tui]
+// collection dump [--folder=<id>]
+// collection folders
+// collection query [<filters>] [--format=<csv|json>]
+// collection stats [<filters>] [--top=<n>] [--format=<csv|json>]
+// collection sync [--countries=<n>]
+// collection add <release_id> [--folder=<id>] [--rating=<n>] [--notes=<text>]
+// collection remove <release_id> <instance_id> [--folder=<id>]
 // discogs --artist=<name>
 // discogs --collection --browse [tui]
 // discogs --collection --dump
 // discogs --collection --search=<query>
 // discogs --release=<id>
 // discogs --search --artist=<artist> --album=<album>
-// files <--move|--transcode>
+// files --split
+// files <--move|--transcode [--target=<codec-setting>] [--allow-lossy-to-lossy] [--archive=<mode>] [--output-root=<dir>] [--replaygain]>
 // lastfm --similar=<artist>
-// library --dump
+// library <--sync [--deep]|--untagged|--below-bitrate=<kbps>>
+// reconcile [--format=<csv|json>]
 // tagger [tui]
+// verify <--library|--source>
+// wantlist <sync|add <release_id> [--notes=<text>]|remove <release_id>|add-missing [--notes=<text>]>
 
 // https://github.com/clap-rs/clap/blob/9d14f394ba22f65f8957310a03ae5fd613f89d76/examples/git-derive.rs
 // https://github.com/atuinsh/atuin/blob/82a7c8d3219749dd298b23bae22456657ee92575/atuin/src/command/client/history.rs#L33
@@ -25,43 +45,479 @@ struct Cli {
 #[derive(Debug, Subcommand)]
 #[command(infer_subcommands = true)] // important!
 enum Commands {
+    /// Modify the Discogs collection
+    Collection {
+        #[command(subcommand)]
+        command: CollectionCommand,
+    },
+
     #[clap(group(
     clap::ArgGroup::new("foo")
         .required(true)
-        .args(&["move", "transcode"]),
+        .args(&["move", "split", "transcode"]),
     ))]
     Files {
         #[clap(action)]
         #[arg(long, short)]
         r#move: bool,
+
+        /// Split single-file images into tracks, according to their cue
+        /// sheets (also done before transcoding)
+        #[clap(action)]
+        #[arg(long)]
+        split: bool,
+
         #[clap(action)]
         #[arg(long, short)]
         transcode: bool,
+
+        /// Override the target of the configured transcode policy, e.g.
+        /// `mp3-v0`, `mp3-320`, `opus-160`
+        #[arg(long, requires = "transcode")]
+        target: Option<Target>,
+
+        /// Allow lossy files to be re-encoded (usually a bad idea)
+        #[clap(action)]
+        #[arg(long, requires = "transcode")]
+        allow_lossy_to_lossy: bool,
+
+        /// What to do with originals after transcoding: `delete`, `keep`,
+        /// `move:<dir>`, `hardlink:<dir>`. Defaults to `delete`, or `keep` with
+        /// `--output-root`
+        #[arg(long, requires = "transcode")]
+        archive: Option<ArchiveMode>,
+
+        /// Write new files into a separate tree, instead of next to the
+        /// originals. Originals are then kept, unless moved with `--archive`
+        #[arg(long, requires = "transcode")]
+        output_root: Option<PathBuf>,
+
+        /// Write ReplayGain tags after transcoding (MP3 and FLAC only). Each
+        /// direct child of the source directory (or of the output root, if
+        /// set) is treated as one album
+        #[clap(action)]
+        #[arg(long, requires = "transcode")]
+        replaygain: bool,
     },
 
     Lastfm {
         #[arg(long, short)]
         similar: String,
     },
+
+    #[clap(group(
+    clap::ArgGroup::new("library")
+        .required(true)
+        .multiple(true)
+        .args(&["sync", "untagged", "below_bitrate"]),
+    ))]
+    Library {
+        /// Incrementally update the library database
+        #[clap(action)]
+        #[arg(long, short)]
+        sync: bool,
+
+        /// Also record every track (audio properties, tags, hash); slow on
+        /// the first run
+        #[clap(action)]
+        #[arg(long, requires = "sync")]
+        deep: bool,
+
+        /// List albums whose tracks carry no Discogs release ID (requires a
+        /// deep sync)
+        #[clap(action)]
+        #[arg(long)]
+        untagged: bool,
+
+        /// List tracks with a bitrate (in kbps) below this (requires a deep
+        /// sync)
+        #[arg(long)]
+        below_bitrate: Option<u32>,
+    },
+
+    /// Compare the Discogs collection with the library (`collection sync`
+    /// first; deep library sync recommended, otherwise all matching is fuzzy)
+    Reconcile {
+        /// `csv` or `json`; plain text if omitted
+        #[arg(long)]
+        format: Option<OutputFormat>,
+    },
+
+    Wantlist {
+        #[command(subcommand)]
+        command: WantlistCommand,
+    },
+
+    /// Fully decode every audio file, and report files that have broken since
+    /// the last run
+    #[clap(group(
+    clap::ArgGroup::new("verify")
+        .required(true)
+        .args(&["library", "source"]),
+    ))]
+    Verify {
+        #[clap(action)]
+        #[arg(long, short)]
+        library: bool,
+        #[clap(action)]
+        #[arg(long, short)]
+        source: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CollectionCommand {
+    /// Add a release; folder, rating and notes default to the `[collection]`
+    /// config
+    Add {
+        release_id: usize,
+        #[arg(long)]
+        folder: Option<usize>,
+        #[arg(long)]
+        rating: Option<u8>,
+        #[arg(long)]
+        notes: Option<String>,
+    },
+    /// Browse the synced collection; the library directory of a release can
+    /// be opened in a shell
+    Browse,
+    /// Dump the collection (or a single folder), including custom fields,
+    /// replacing the contents of the collection database
+    Dump {
+        #[arg(long, default_value_t = 0)]
+        folder: usize,
+    },
+    /// List folders, with the number of releases in each
+    Folders,
+    /// List instances matching all filters (as a table, unless `--format`
+    /// is given)
+    Query {
+        #[command(flatten)]
+        filter: Filter,
+        #[arg(long)]
+        format: Option<OutputFormat>,
+    },
+    /// Breakdowns by genre, style, decade, label, format, country, rating
+    /// and artist
+    Stats {
+        #[command(flatten)]
+        filter: Filter,
+        /// Number of values shown per breakdown
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        #[arg(long)]
+        format: Option<OutputFormat>,
+    },
+    /// Fetch only the releases added since the last sync, then dump to
+    /// sqlite
+    Sync {
+        /// Also look up the country of (at most) this many releases, which
+        /// the collection does not provide
+        #[arg(long, default_value_t = 0)]
+        countries: usize,
+    },
+    /// Remove a single instance of a release
+    Remove {
+        release_id: usize,
+        instance_id: usize,
+        #[arg(long, default_value_t = 0)]
+        folder: usize,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum WantlistCommand {
+    /// Replace the local copy of the wantlist
+    Sync,
+    Add {
+        release_id: usize,
+        #[arg(long)]
+        notes: Option<String>,
+    },
+    Remove {
+        release_id: usize,
+    },
+    /// Want every master that is in the library, but not in the collection
+    AddMissing {
+        #[arg(long)]
+        notes: Option<String>,
+        /// Only list the releases that would be added
+        #[clap(action)]
+        #[arg(long)]
+        dry_run: bool,
+    },
 }
 
 pub fn main() {
     let args = Cli::parse();
     match args.command {
+        Commands::Collection {
+            command:
+                CollectionCommand::Add {
+                    release_id,
+                    folder,
+                    rating,
+                    notes,
+                },
+        } => {
+            let mut cfg = CONFIG.collection.clone();
+            cfg.folder_id = folder.unwrap_or(cfg.folder_id);
+            cfg.rating = rating.or(cfg.rating);
+            cfg.notes = notes.or(cfg.notes);
+            let instance = cfg.add(release_id).unwrap();
+            println!("{instance:?}");
+        }
+        Commands::Collection {
+            command: CollectionCommand::Browse,
+        } => crate::browser::main(),
+        Commands::Collection {
+            command: CollectionCommand::Dump { folder },
+        } => {
+            use crate::collection::Collection;
+
+            let coll = Collection::new().with_folder(folder).dump().unwrap();
+            coll.to_sql(
+                &CONFIG.db_path("collection.db"),
+                &Collection::folders().unwrap(),
+                &Collection::fields().unwrap(),
+            )
+            .unwrap();
+            println!("{} instance(s) dumped", coll.releases.len());
+        }
+        Commands::Collection {
+            command: CollectionCommand::Folders,
+        } => {
+            use crate::collection::Collection;
+
+            for f in Collection::folders().unwrap() {
+                println!("{}\t{}\t{}", f.id, f.name, f.count);
+            }
+        }
+        Commands::Collection {
+            command: CollectionCommand::Query { filter, format },
+        } => {
+            use crate::collection::Collection;
+            use crate::query;
+            use crate::query::QueryRow;
+
+            let coll = Collection::from_sql(&CONFIG.db_path("collection.db")).unwrap();
+            let rows: Vec<QueryRow> = filter
+                .apply(&coll.releases)
+                .into_iter()
+                .map(QueryRow::from)
+                .collect();
+            query::write_rows(&rows, format, std::io::stdout().lock()).unwrap();
+        }
+        Commands::Collection {
+            command:
+                CollectionCommand::Stats {
+                    filter,
+                    top,
+                    format,
+                },
+        } => {
+            use crate::collection::Collection;
+            use crate::query::Stats;
+
+            let coll = Collection::from_sql(&CONFIG.db_path("collection.db")).unwrap();
+            Stats::new(&filter.apply(&coll.releases), top)
+                .write(format, std::io::stdout().lock())
+                .unwrap();
+        }
+        Commands::Collection {
+            command: CollectionCommand::Sync { countries },
+        } => {
+            use crate::collection::Collection;
+
+            let db_path = CONFIG.db_path("collection.db");
+            let mut coll = Collection::from_sql(&db_path).unwrap();
+            let result = coll.sync().unwrap();
+            if countries > 0 {
+                let filled = coll.fill_countries(countries);
+                println!("{filled} countries looked up");
+            }
+            coll.to_sql(
+                &db_path,
+                &Collection::folders().unwrap(),
+                &Collection::fields().unwrap(),
+            )
+            .unwrap();
+            println!("{result:?}");
+        }
+        Commands::Collection {
+            command:
+                CollectionCommand::Remove {
+                    release_id,
+                    instance_id,
+                    folder,
+                },
+        } => {
+            use crate::collection::CollectionInstance;
+
+            CollectionInstance {
+                folder_id: folder,
+                release_id,
+                instance_id,
+            }
+            .remove()
+            .unwrap();
+        }
         Commands::Files { r#move: true, .. } => todo!(),
+        Commands::Files { split: true, .. } => {
+            use crate::io::SOURCE;
+            use crate::transcode::SourceDir;
+            SourceDir::new(&SOURCE)
+                .unwrap()
+                .split_cues(&CONFIG.transcode)
+                .unwrap();
+        }
         Commands::Files {
-            transcode: true, ..
+            transcode: true,
+            target,
+            allow_lossy_to_lossy,
+            archive,
+            output_root,
+            replaygain,
+            ..
         } => {
             // 11k, all skip: 0.2 s (rust), 0.6 s (python)
             use crate::io::SOURCE;
             use crate::transcode::SourceDir;
-            SourceDir::new(&SOURCE).unwrap().transcode_all().unwrap();
+
+            let mut policy = CONFIG.transcode.clone();
+            if let Some(target) = target {
+                policy = policy.with_target(target);
+            }
+            if allow_lossy_to_lossy {
+                policy = policy.with_lossy_to_lossy(true);
+            }
+            if let Some(archive) = archive {
+                policy = policy.with_archive(archive);
+            }
+            if let Some(root) = output_root {
+                policy = policy.with_output_root(root);
+            }
+
+            SourceDir::new(&SOURCE)
+                .unwrap()
+                .with_replaygain(replaygain || CONFIG.replaygain)
+                .transcode_all(&policy)
+                .unwrap();
         }
         Commands::Lastfm { similar: artist } => {
             let mut t = crate::lastfm::ArtistTree::new(&artist);
             t.build();
             t.as_dot(crate::lastfm::DotOutput::Svg).unwrap();
         }
+        Commands::Library {
+            sync,
+            deep,
+            untagged,
+            below_bitrate,
+        } => {
+            use crate::io::LibraryDB;
+            use crate::io::LIBRARY_ROOT;
+
+            let db = LibraryDB::load(&CONFIG.db_path("library.db")).unwrap();
+            if sync {
+                let stats = db.sync(&LIBRARY_ROOT).unwrap();
+                println!("{stats:?}");
+            }
+            if deep {
+                let scanned = db.deep_scan(&LIBRARY_ROOT).unwrap();
+                println!("{scanned} track(s) scanned");
+            }
+            if untagged {
+                for album in db.untagged_albums().unwrap() {
+                    println!("{album}");
+                }
+            }
+            if let Some(kbps) = below_bitrate {
+                for track in db.tracks_below(kbps).unwrap() {
+                    println!("{track}");
+                }
+            }
+        }
+        Commands::Reconcile { format } => {
+            use crate::collection::Collection;
+            use crate::io::LibraryDB;
+            use crate::reconcile;
+
+            let db = LibraryDB::load(&CONFIG.db_path("library.db")).unwrap();
+            let collection = Collection::from_sql(&CONFIG.db_path("collection.db")).unwrap();
+            reconcile::reconcile(
+                &collection.releases,
+                &db.entries,
+                &db.release_ids().unwrap(),
+            )
+            .write(format, std::io::stdout().lock())
+            .unwrap();
+        }
+        Commands::Verify { library, .. } => {
+            use crate::io::Library;
+            use crate::io::Walk;
+            use crate::io::LIBRARY_ROOT;
+            use crate::io::SOURCE;
+            use crate::transcode::SourceDir;
+            use crate::verify;
+            use crate::verify::VerifyDB;
+
+            let db = VerifyDB::new(&CONFIG.db_path("verify.db"));
+            let broken = match library {
+                true => verify::verify_all(Library::new(&LIBRARY_ROOT).walk(), &db),
+                false => {
+                    verify::verify_all(std::iter::once(SourceDir::new(&SOURCE).unwrap().dir), &db)
+                }
+            }
+            .unwrap();
+
+            println!("{} newly broken file(s)", broken.len());
+            for r in broken {
+                println!("{r}");
+            }
+        }
+        Commands::Wantlist { command } => {
+            use crate::wantlist::Wantlist;
+
+            match command {
+                WantlistCommand::Sync => {
+                    let wantlist = Wantlist::dump().unwrap();
+                    wantlist.to_sql(&CONFIG.db_path("wantlist.db")).unwrap();
+                    println!("{} release(s) wanted", wantlist.wants.len());
+                }
+                WantlistCommand::Add { release_id, notes } => {
+                    Wantlist::add(release_id, notes.as_deref()).unwrap()
+                }
+                WantlistCommand::Remove { release_id } => Wantlist::remove(release_id).unwrap(),
+                WantlistCommand::AddMissing { notes, dry_run } => {
+                    use crate::collection::Collection;
+                    use crate::io::LibraryDB;
+                    use crate::reconcile;
+
+                    let db = LibraryDB::load(&CONFIG.db_path("library.db")).unwrap();
+                    let collection =
+                        Collection::from_sql(&CONFIG.db_path("collection.db")).unwrap();
+                    let report = reconcile::reconcile(
+                        &collection.releases,
+                        &db.entries,
+                        &db.release_ids().unwrap(),
+                    );
+                    let added = Wantlist::dump()
+                        .unwrap()
+                        .add_missing_masters(
+                            &report,
+                            &collection.releases,
+                            notes.as_deref(),
+                            dry_run,
+                        )
+                        .unwrap();
+                    match dry_run {
+                        true => println!("{} release(s) would be added to wantlist", added.len()),
+                        false => println!("{} release(s) added to wantlist", added.len()),
+                    }
+                }
+            }
+        }
         _ => unimplemented!(),
     }
 }
@@ -1,34 +1,64 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use anyhow::Context;
+use reqwest::Method;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::json;
 
 use crate::http;
 use crate::release::Artist;
+use crate::release::Format;
 use crate::release::Label;
-// use crate::search::Page;
+use crate::release::Release;
+use crate::search::Page;
+
+/// The maximum allowed by Discogs
+const PER_PAGE: usize = 250;
 
 /// Does not contain tracklist.
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct CollectionRelease {
     /// Equivalent to CollectionResult.id
     pub id: usize,
-    master_id: usize,
-    artists: Vec<Artist>,
-    genres: Vec<String>,
-    labels: Vec<Label>,
-    master_url: Option<String>,
-    resource_url: String,
-    styles: Vec<String>,
-    title: String,
-    year: usize,
-    //     formats: Array ,
+    pub master_id: usize,
+    pub artists: Vec<Artist>,
+    pub genres: Vec<String>,
+    pub labels: Vec<Label>,
+    pub master_url: Option<String>,
+    pub resource_url: String,
+    pub styles: Vec<String>,
+    pub title: String,
+    /// 0 if unknown
+    pub year: usize,
+    #[serde(default)]
+    pub formats: Vec<Format>,
+    /// Not returned by the collection endpoints; see
+    /// `Collection::fill_countries`
+    #[serde(default)]
+    pub country: Option<String>,
+}
+
+/// Value of a custom field, for a single instance. Fields that were never set
+/// are omitted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldValue {
+    pub field_id: usize,
+    pub value: String,
 }
 
+/// One instance of a release; a release that was added twice appears twice.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct CollectionResult {
     pub id: usize,
-    instance_id: usize,
-    rating: u8,
-    date_added: String,
+    pub instance_id: usize,
+    pub folder_id: usize,
+    pub rating: u8,
+    pub date_added: String,
+    /// Custom field values; only returned to the owner of the collection
+    #[serde(default)]
+    pub notes: Vec<FieldValue>,
 
     // TODO: flatten basic_information, i.e. bring CollectionRelease's fields into CollectionResult
 
@@ -37,19 +67,114 @@ pub struct CollectionResult {
     pub basic_information: CollectionRelease,
 }
 
+impl CollectionResult {
+    /// Value of the custom field called `name`
+    pub fn field(
+        &self,
+        fields: &[CustomField],
+        name: &str,
+    ) -> Option<&str> {
+        let field = fields.iter().find(|f| f.name == name)?;
+        self.notes
+            .iter()
+            .find(|v| v.field_id == field.id)
+            .map(|v| v.value.as_str())
+    }
+
+    pub fn media_condition(
+        &self,
+        fields: &[CustomField],
+    ) -> Option<&str> {
+        self.field(fields, MEDIA_CONDITION)
+    }
+
+    pub fn sleeve_condition(
+        &self,
+        fields: &[CustomField],
+    ) -> Option<&str> {
+        self.field(fields, SLEEVE_CONDITION)
+    }
+}
+
+/// Names of the custom fields every collection starts with
+pub const MEDIA_CONDITION: &str = "Media Condition";
+pub const SLEEVE_CONDITION: &str = "Sleeve Condition";
+pub const NOTES: &str = "Notes";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Folder {
+    pub id: usize,
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FieldKind {
+    /// e.g. media/sleeve condition
+    Dropdown { options: Vec<String> },
+    /// Free text, e.g. notes
+    Textarea { lines: usize },
+}
+
+/// Definition of a user-defined field; values are stored per instance (see
+/// `FieldValue`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CustomField {
+    pub id: usize,
+    pub name: String,
+    pub position: usize,
+    pub public: bool,
+    #[serde(flatten)]
+    pub kind: FieldKind,
+}
+
+impl CustomField {
+    /// Dropdown values must be one of the options; Discogs silently ignores
+    /// anything else.
+    pub fn check(
+        &self,
+        value: &str,
+    ) -> anyhow::Result<()> {
+        match &self.kind {
+            FieldKind::Dropdown { options } if !options.iter().any(|o| o == value) => {
+                anyhow::bail!("{:?} is not a valid {}: {:?}", value, self.name, options)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A single page of `/collection/folders/{id}/releases`
+#[derive(Deserialize, Debug)]
+struct CollectionPage {
+    pagination: Page,
+    releases: Vec<CollectionResult>,
+}
+
+/// How `Collection::sync` brought the collection up to date
+#[derive(Debug, PartialEq)]
+pub enum CollectionSync {
+    /// Only the given number of new instances were fetched
+    Incremental(usize),
+    /// Instances were removed since the last sync (or the count was otherwise
+    /// off), so everything was fetched again
+    Full(usize),
+}
+
 /// Contains zero or more `CollectionResult`s, each of which contains one
-/// `CollectionRelease`.
+/// `CollectionRelease`. Instances are ordered by `date_added`, newest first.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
-// #[serde(transparent)]
 pub struct Collection {
-    // ignoring pagination is a (poor) hack to reuse this json struct for our own purposes; we
-    // don't actually ever check it
-    // pub pagination: Page,
     pub releases: Vec<CollectionResult>,
 
     /// This is only for testing, and should never be needed in real use
     #[serde(skip)]
     start_page: usize,
+
+    /// 0 is the "All" folder
+    #[serde(skip)]
+    folder_id: usize,
 }
 
 impl Default for Collection {
@@ -57,6 +182,7 @@ impl Default for Collection {
         Self {
             releases: vec![],
             start_page: 1,
+            folder_id: 0,
         }
     }
 }
@@ -72,29 +198,368 @@ impl Collection {
         self
     }
 
-    /// Returns Err if current page exceeded the allowed range
+    pub fn with_releases(
+        mut self,
+        releases: Vec<CollectionResult>,
+    ) -> Self {
+        self.releases = releases;
+        self
+    }
+
+    /// Only dump the folder `folder_id`
+    pub fn with_folder(
+        mut self,
+        folder_id: usize,
+    ) -> Self {
+        self.folder_id = folder_id;
+        self
+    }
+
+    /// 0 ("All") unless set with `with_folder`
+    pub fn folder_id(&self) -> usize { self.folder_id }
+
+    pub fn folders() -> anyhow::Result<Vec<Folder>> {
+        let resp = http::make_request(http::RequestType::Collection, "collection/folders")?
+            .error_for_status()?;
+        let mut json = http::parse_json(resp);
+        Ok(serde_json::from_value(json["folders"].take())?)
+    }
+
+    /// Custom field definitions, ordered by position
+    pub fn fields() -> anyhow::Result<Vec<CustomField>> {
+        let resp = http::make_request(http::RequestType::Collection, "collection/fields")?
+            .error_for_status()?;
+        let mut json = http::parse_json(resp);
+        let mut fields: Vec<CustomField> = serde_json::from_value(json["fields"].take())?;
+        fields.sort_by_key(|f| f.position);
+        Ok(fields)
+    }
+
+    /// All instances of the release `release_id`
+    pub fn instances(
+        &self,
+        release_id: usize,
+    ) -> Vec<&CollectionResult> {
+        self.releases
+            .iter()
+            .filter(|r| r.id == release_id)
+            .collect()
+    }
+
+    fn fetch_page(
+        &self,
+        page: usize,
+    ) -> anyhow::Result<CollectionPage> {
+        let url = format!(
+            "collection/folders/{}/releases?per_page={PER_PAGE}&page={page}&sort=added&sort_order=desc",
+            self.folder_id
+        );
+        let resp = http::make_request(http::RequestType::Collection, &url)?.error_for_status()?;
+        serde_json::from_str(&resp.text()?).with_context(|| format!("parse collection page {page}"))
+    }
+
+    /// Fetch every page, newest first. Any failed request is an error, as a
+    /// partial dump is indistinguishable from a smaller collection.
     pub fn dump(&self) -> anyhow::Result<Self> {
         let mut releases = vec![];
 
         let mut i = self.start_page;
         loop {
-            let url = format!("/collection/folders/0/releases?per_page=250&page={i}");
-            let resp = http::make_request(http::RequestType::Collection, &url)?;
-            match serde_json::from_str::<Collection>(resp.text()?.as_str()) {
-                Ok(mut coll) => {
-                    // println!("{} {}", i, coll.releases.len());
-                    releases.append(&mut coll.releases);
-                    i += 1;
-                }
-                Err(_) => break,
+            let mut page = self.fetch_page(i)?;
+            releases.append(&mut page.releases);
+            if i >= page.pagination.pages {
+                break;
             }
+            i += 1;
         }
 
         Ok(Collection {
             releases,
             start_page: 1,
+            folder_id: self.folder_id,
         })
-        // TODO: transform into sql
+    }
+
+    /// Bring a previous dump up to date. Pages are fetched (newest first) only
+    /// until an already known instance is reached. Removals cannot be seen
+    /// this way, so if the total then differs from the count reported by
+    /// Discogs, everything is fetched again.
+    pub fn sync(&mut self) -> anyhow::Result<CollectionSync> {
+        let known: HashSet<usize> = self.releases.iter().map(|r| r.instance_id).collect();
+        let mut added = vec![];
+
+        let mut i = 1;
+        let total = loop {
+            let page = self.fetch_page(i)?;
+            let (mut newer, reached) = newer_than(page.releases, &known);
+            added.append(&mut newer);
+            if reached || i >= page.pagination.pages {
+                break page.pagination.items;
+            }
+            i += 1;
+        };
+
+        if known.len() + added.len() != total {
+            let countries: HashMap<usize, String> = self
+                .releases
+                .drain(..)
+                .filter_map(|r| Some((r.id, r.basic_information.country?)))
+                .collect();
+            *self = Collection::new().with_folder(self.folder_id).dump()?;
+            for r in self.releases.iter_mut() {
+                r.basic_information.country = countries.get(&r.id).cloned();
+            }
+            return Ok(CollectionSync::Full(self.releases.len()));
+        }
+
+        let count = added.len();
+        added.append(&mut self.releases);
+        self.releases = added;
+        Ok(CollectionSync::Incremental(count))
+    }
+
+    /// Country is only available from the release itself, which costs a
+    /// request per release; at most `limit` releases are fetched, so this can
+    /// be done over several runs. Returns the number of releases fetched.
+    pub fn fill_countries(
+        &mut self,
+        limit: usize,
+    ) -> usize {
+        let missing: HashSet<usize> = self
+            .releases
+            .iter()
+            .filter(|r| r.basic_information.country.is_none())
+            .map(|r| r.id)
+            .take(limit)
+            .collect();
+        let countries: HashMap<usize, String> = missing
+            .iter()
+            .filter_map(|id| Some((*id, Release::get(*id)?.country.unwrap_or_default())))
+            .collect();
+        for r in self.releases.iter_mut() {
+            if let Some(country) = countries.get(&r.id) {
+                r.basic_information.country = Some(country.clone());
+            }
+        }
+        countries.len()
+    }
+}
+
+/// Instances (newest first) preceding the first known one, and whether a known
+/// one was reached at all
+fn newer_than(
+    page: Vec<CollectionResult>,
+    known: &HashSet<usize>,
+) -> (Vec<CollectionResult>, bool) {
+    let len = page.len();
+    let newer: Vec<CollectionResult> = page
+        .into_iter()
+        .take_while(|r| !known.contains(&r.instance_id))
+        .collect();
+    let reached = newer.len() < len;
+    (newer, reached)
+}
+
+/// A single copy of a release in the collection. The same release may be
+/// added more than once, so the instance ID is required to modify (or remove)
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollectionInstance {
+    pub folder_id: usize,
+    pub release_id: usize,
+    pub instance_id: usize,
+}
+
+impl CollectionInstance {
+    /// Folder 0 ("All") cannot be added to; folder 1 is "Uncategorized".
+    pub fn add(
+        folder_id: usize,
+        release_id: usize,
+    ) -> anyhow::Result<Self> {
+        let url = format!("collection/folders/{folder_id}/releases/{release_id}");
+        let resp = http::send(Method::POST, http::RequestType::Collection, &url, None)?
+            .error_for_status()?;
+        let instance_id = http::parse_json(resp)["instance_id"]
+            .as_u64()
+            .context("no instance_id in response")? as usize;
+        Ok(Self {
+            folder_id,
+            release_id,
+            instance_id,
+        })
+    }
+
+    /// Instances of the release already in the collection, in any folder
+    pub fn existing(release_id: usize) -> anyhow::Result<Vec<Self>> {
+        let url = format!("collection/releases/{release_id}");
+        let resp = http::make_request(http::RequestType::Collection, &url)?.error_for_status()?;
+        let page: CollectionPage = serde_json::from_str(&resp.text()?)?;
+        Ok(page
+            .releases
+            .iter()
+            .map(|r| Self {
+                folder_id: r.folder_id,
+                release_id: r.id,
+                instance_id: r.instance_id,
+            })
+            .collect())
+    }
+
+    fn url(&self) -> String {
+        format!(
+            "collection/folders/{}/releases/{}/instances/{}",
+            self.folder_id, self.release_id, self.instance_id
+        )
+    }
+
+    fn post(
+        &self,
+        url: &str,
+        body: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        http::send(
+            Method::POST,
+            http::RequestType::Collection,
+            url,
+            Some(&body),
+        )?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// 1-5, or 0 to clear
+    pub fn set_rating(
+        &self,
+        rating: u8,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(rating <= 5, "rating must be between 0 and 5");
+        self.post(&self.url(), json!({ "rating": rating }))
+    }
+
+    /// Custom fields are defined per user; see
+    /// `/users/{username}/collection/fields` for their IDs.
+    pub fn set_field(
+        &self,
+        field_id: usize,
+        value: &str,
+    ) -> anyhow::Result<()> {
+        let url = format!("{}/fields/{field_id}", self.url());
+        self.post(&url, json!({ "value": value }))
+    }
+
+    pub fn remove(&self) -> anyhow::Result<()> {
+        http::send(
+            Method::DELETE,
+            http::RequestType::Collection,
+            &self.url(),
+            None,
+        )?
+        .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// What to do with a release once an album has been tagged with it, set in the
+/// `[collection]` section of the config:
+///
+/// ```toml
+/// [collection]
+/// add_after_tagging = true
+/// folder_id = 1
+/// rating = 4
+/// notes = "ripped"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CollectionConfig {
+    /// Releases already in the collection (in any folder) are not added again
+    pub add_after_tagging: bool,
+    pub folder_id: usize,
+    pub rating: Option<u8>,
+    pub notes: Option<String>,
+    /// The default "Notes" field is 3
+    pub notes_field_id: usize,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        Self {
+            add_after_tagging: false,
+            folder_id: 1,
+            rating: None,
+            notes: None,
+            notes_field_id: 3,
+        }
+    }
+}
+
+impl CollectionConfig {
+    /// Add the release to the configured folder, then set rating and notes
+    /// (if any).
+    pub fn add(
+        &self,
+        release_id: usize,
+    ) -> anyhow::Result<CollectionInstance> {
+        let instance = CollectionInstance::add(self.folder_id, release_id)?;
+        if let Some(rating) = self.rating {
+            instance.set_rating(rating)?;
+        }
+        if let Some(notes) = &self.notes {
+            instance.set_field(self.notes_field_id, notes)?;
+        }
+        Ok(instance)
+    }
+
+    /// Like `add`, but only if the release is not in the collection yet, so
+    /// that tagging an album twice does not add it twice. None if it already
+    /// was.
+    pub fn add_if_missing(
+        &self,
+        release_id: usize,
+    ) -> anyhow::Result<Option<CollectionInstance>> {
+        match CollectionInstance::existing(release_id)?.is_empty() {
+            true => Ok(Some(self.add(release_id)?)),
+            false => Ok(None),
+        }
+    }
+}
+
+/// An instance of a release by `artist`, without any other metadata; fields
+/// are set on the result as needed.
+#[cfg(test)]
+pub fn test_instance(
+    instance_id: usize,
+    release_id: usize,
+    artist: &str,
+) -> CollectionResult {
+    CollectionResult {
+        id: release_id,
+        instance_id,
+        folder_id: 1,
+        rating: 0,
+        date_added: String::new(),
+        notes: vec![],
+        basic_information: CollectionRelease {
+            id: release_id,
+            master_id: 0,
+            artists: vec![Artist {
+                anv: String::new(),
+                id: 1,
+                name: artist.to_string(),
+                resource_url: String::new(),
+                role: String::new(),
+                tracks: String::new(),
+            }],
+            genres: vec![],
+            labels: vec![],
+            master_url: None,
+            resource_url: String::new(),
+            styles: vec![],
+            title: String::new(),
+            year: 0,
+            formats: vec![],
+            country: None,
+        },
     }
 }
 
@@ -109,4 +574,70 @@ mod tests {
         assert_eq!(r.id, r.basic_information.id);
         assert_eq!(coll.releases.len(), 163);
     }
+
+    #[test]
+    fn test_newer_than() {
+        use std::collections::HashSet;
+
+        use crate::collection::newer_than;
+        use crate::collection::test_instance;
+
+        let instance = |instance_id| test_instance(instance_id, 1, "");
+        let known = HashSet::from([2, 1]);
+
+        let (newer, reached) = newer_than(vec![instance(4), instance(3), instance(2)], &known);
+        assert_eq!(newer.len(), 2);
+        assert!(reached);
+
+        let (newer, reached) = newer_than(vec![instance(6), instance(5)], &known);
+        assert_eq!(newer.len(), 2);
+        assert!(!reached);
+    }
+
+    #[test]
+    fn test_custom_fields() {
+        use crate::collection::test_instance;
+        use crate::collection::CustomField;
+        use crate::collection::FieldKind;
+        use crate::collection::FieldValue;
+
+        let fields: Vec<CustomField> = serde_json::from_str(
+            r#"[
+                {"id": 1, "name": "Media Condition", "type": "dropdown", "position": 1,
+                 "public": true, "options": ["Mint (M)", "Near Mint (NM or M-)"]},
+                {"id": 3, "name": "Notes", "type": "textarea", "position": 3,
+                 "public": false, "lines": 3}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(fields[1].kind, FieldKind::Textarea { lines: 3 });
+        assert!(fields[0].check("Mint (M)").is_ok());
+        assert!(fields[0].check("mint").is_err());
+        assert!(fields[1].check("anything").is_ok());
+
+        let mut instance = test_instance(2, 1, "");
+        instance.notes = vec![FieldValue {
+            field_id: 1,
+            value: "Mint (M)".to_string(),
+        }];
+        assert_eq!(instance.media_condition(&fields), Some("Mint (M)"));
+        assert_eq!(instance.sleeve_condition(&fields), None);
+    }
+
+    #[test]
+    fn test_collection_config() {
+        use crate::collection::CollectionConfig;
+        use crate::config::Config;
+
+        let cfg = Config::parse("[collection]\nadd_after_tagging = true\nrating = 4").unwrap();
+        assert_eq!(
+            cfg.collection,
+            CollectionConfig {
+                add_after_tagging: true,
+                rating: Some(4),
+                ..Default::default()
+            }
+        );
+        assert_eq!(Config::parse("").unwrap().collection.folder_id, 1);
+    }
 }
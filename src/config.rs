@@ -0,0 +1,121 @@
+//! User configuration, read from `~/.config/coggers/config.toml` (or
+//! `$XDG_CONFIG_HOME/coggers/config.toml`). Every section is optional; missing
+//! sections and fields fall back to their defaults.
+//!
+//! ```toml
+//! data_dir = "~/.local/share/coggers"
+//! replaygain = true
+//!
+//! [collection]
+//! add_after_tagging = true
+//! rating = 4
+//!
+//! [tagger.keys]
+//! top = ["g", "Home"]
+//!
+//! [transcode]
+//! allow_lossy_to_lossy = false
+//! archive = { move = "/mnt/lossless" }
+//! output_root = "/mnt/mp3"
+//!
+//! [[transcode.rules]]
+//! input = "FLAC"
+//! action = { transcode = { mp3_vbr = 0 } }
+//!
+//! [[transcode.rules]]
+//! input = "MP3"
+//! min_bitrate = 320
+//! action = "copy"
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::collection::CollectionConfig;
+use crate::keymap::TaggerConfig;
+use crate::transcode::TranscodePolicy;
+
+lazy_static! {
+    /// Loaded once; a missing config file is not an error, but an invalid one is
+    /// reported and then ignored.
+    pub static ref CONFIG: Config = match Config::load() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("ignoring config: {e:#}");
+            Config::default()
+        }
+    };
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// Where databases are stored. Defaults to `$XDG_DATA_HOME/coggers` (or
+    /// `~/.local/share/coggers`).
+    pub data_dir: PathBuf,
+    /// Write ReplayGain/R128 tags after transcoding or tagging
+    pub replaygain: bool,
+    pub transcode: TranscodePolicy,
+    pub collection: CollectionConfig,
+    pub tagger: TaggerConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let data_dir = env::var("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|h| PathBuf::from(h).join(".local/share")))
+            .unwrap_or_default()
+            .join("coggers");
+        Self {
+            data_dir,
+            replaygain: false,
+            transcode: TranscodePolicy::default(),
+            collection: CollectionConfig::default(),
+            tagger: TaggerConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        let base = env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|h| PathBuf::from(h).join(".config")))
+            .ok()?;
+        Some(base.join("coggers").join("config.toml"))
+    }
+
+    pub fn load() -> Result<Self> {
+        match Self::path() {
+            Some(p) if p.exists() => Self::parse(&fs::read_to_string(&p)?)
+                .with_context(|| format!("parse {}", p.display())),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut cfg: Self = toml::from_str(s)?;
+        if let Ok(stripped) = cfg.data_dir.strip_prefix("~") {
+            cfg.data_dir = PathBuf::from(env::var("HOME")?).join(stripped);
+        }
+        cfg.transcode.validate()?;
+        Ok(cfg)
+    }
+
+    /// Path to the sqlite database `name` in `data_dir`, which is created if
+    /// necessary.
+    pub fn db_path(
+        &self,
+        name: &str,
+    ) -> String {
+        let _ = fs::create_dir_all(&self.data_dir);
+        self.data_dir.join(name).to_string_lossy().to_string()
+    }
+}
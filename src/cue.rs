@@ -0,0 +1,504 @@
+//! CUE sheet parsing, and splitting of single-file images (`album.flac` +
+//! `album.cue`) into one file per track.
+//!
+//! Only the subset of the format that rippers actually produce is supported:
+//! `REM`, `CATALOG`, `PERFORMER`, `TITLE`, `FILE`, `TRACK`, `ISRC` and `INDEX`.
+//! Multi-file sheets are supported, including the non-compliant EAC layout,
+//! where the `INDEX 00` of a track refers to the end of the previous file.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::Context;
+use anyhow::Result;
+use lofty::AudioFile;
+
+/// CD frames (sectors) per second
+const FRAMES_PER_SEC: u32 = 75;
+
+/// A position in one of the sheet's files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CueIndex {
+    /// Index into `CueSheet.files`
+    pub file: usize,
+    /// In CD frames (1/75 s)
+    pub frames: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub isrc: Option<String>,
+    /// `INDEX 00`; the pregap runs from here to `start`
+    pub pregap: Option<CueIndex>,
+    /// `INDEX 01`
+    pub start: Option<CueIndex>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Should be a UPC/EAN barcode, but some rippers put the label's catalog
+    /// number here
+    pub catalog: Option<String>,
+    /// `REM` fields, e.g. `GENRE`, `DATE`, `DISCID`, keyed in uppercase
+    pub rem: HashMap<String, String>,
+    /// Relative to the directory of the sheet
+    pub files: Vec<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+/// Where pregaps (`INDEX 00` to `INDEX 01`) end up when splitting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GapMode {
+    /// At the end of the previous track (the EAC default)
+    Append,
+    /// At the start of the track itself
+    Prepend,
+}
+
+/// An image split by `CueSheet::split`. The caller is expected to verify the
+/// tracks before archiving the image.
+#[derive(Debug)]
+pub struct Split {
+    pub image: PathBuf,
+    /// In track order
+    pub tracks: Vec<PathBuf>,
+    /// Milliseconds of audio before the first track (e.g. a hidden track in
+    /// the pregap), which is not split out
+    pub skipped: u64,
+}
+
+/// A contiguous range of audio that becomes one track. `end` is `None` if the
+/// track runs until the end of the file.
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub track: usize,
+    pub file: usize,
+    pub start: u32,
+    pub end: Option<u32>,
+}
+
+/// Remove surrounding quotes, if any
+fn unquote(s: &str) -> String { s.trim().trim_matches('"').to_string() }
+
+/// `mm:ss:ff` to frames
+fn parse_time(s: &str) -> Result<u32> {
+    let parts: Vec<u32> = s
+        .split(':')
+        .map(|p| p.parse::<u32>())
+        .collect::<Result<_, _>>()?;
+    match parts[..] {
+        [m, s, f] => Ok((m * 60 + s) * FRAMES_PER_SEC + f),
+        _ => anyhow::bail!("invalid time: {s}"),
+    }
+}
+
+impl CueSheet {
+    pub fn load(path: &Path) -> Result<Self> {
+        // cue sheets are frequently not utf-8 (usually cp1252)
+        let bytes = fs::read(path)?;
+        Self::parse(&String::from_utf8_lossy(&bytes))
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut sheet = CueSheet::default();
+
+        for line in s.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim();
+            let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let curr_file = sheet.files.len().checked_sub(1);
+
+            match (cmd.to_uppercase().as_str(), sheet.tracks.last_mut()) {
+                ("REM", _) => {
+                    if let Some((key, val)) = rest.split_once(' ') {
+                        sheet.rem.insert(key.to_uppercase(), unquote(val));
+                    }
+                }
+                ("CATALOG", _) => sheet.catalog = Some(unquote(rest)),
+                ("FILE", _) => {
+                    // FILE "name with spaces.flac" WAVE
+                    let name = match rest.rsplit_once(' ') {
+                        Some((name, _filetype)) => name,
+                        None => rest,
+                    };
+                    sheet.files.push(unquote(name));
+                }
+                ("TRACK", _) => {
+                    let number = rest
+                        .split_whitespace()
+                        .next()
+                        .context("no track number")?
+                        .parse()?;
+                    sheet.tracks.push(CueTrack {
+                        number,
+                        ..Default::default()
+                    });
+                }
+                ("TITLE", Some(track)) => track.title = Some(unquote(rest)),
+                ("TITLE", None) => sheet.title = Some(unquote(rest)),
+                ("PERFORMER", Some(track)) => track.performer = Some(unquote(rest)),
+                ("PERFORMER", None) => sheet.performer = Some(unquote(rest)),
+                ("ISRC", Some(track)) => track.isrc = Some(unquote(rest)),
+                ("INDEX", Some(track)) => {
+                    let (num, time) = rest.trim().split_once(' ').context("invalid INDEX")?;
+                    let index = CueIndex {
+                        file: curr_file.context("INDEX before FILE")?,
+                        frames: parse_time(time.trim())?,
+                    };
+                    match num.parse::<u32>()? {
+                        0 => track.pregap = Some(index),
+                        1 => track.start = Some(index),
+                        _ => {} // subindexes are irrelevant for splitting
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        anyhow::ensure!(!sheet.files.is_empty(), "no FILE in cue sheet");
+        anyhow::ensure!(
+            sheet.tracks.iter().all(|t| t.start.is_some()),
+            "track without INDEX 01"
+        );
+        Ok(sheet)
+    }
+
+    /// `CATALOG`, if it looks like a UPC/EAN
+    pub fn barcode(&self) -> Option<&str> {
+        self.catalog
+            .as_deref()
+            .filter(|c| (12..=13).contains(&c.len()) && c.chars().all(|c| c.is_ascii_digit()))
+    }
+
+    /// `REM CATALOGNUMBER`, or `CATALOG` if it does not look like a barcode
+    pub fn catno(&self) -> Option<&str> {
+        self.rem
+            .get("CATALOGNUMBER")
+            .map(|c| c.as_str())
+            .or(self.catalog.as_deref().filter(|_| self.barcode().is_none()))
+    }
+
+    /// Only files containing more than one track need to be split.
+    pub fn is_image(&self) -> bool {
+        (0..self.files.len()).any(|f| {
+            self.tracks
+                .iter()
+                .filter(|t| t.start.is_some_and(|s| s.file == f))
+                .count()
+                > 1
+        })
+    }
+
+    /// Compute the range of each track. A track always ends where the next one
+    /// starts (according to `gaps`), if both are in the same file; otherwise,
+    /// it runs until the end of its file.
+    pub fn segments(
+        &self,
+        gaps: GapMode,
+    ) -> Vec<Segment> {
+        // where each track begins, after gap handling
+        let begin = |t: &CueTrack| -> CueIndex {
+            let start = t.start.unwrap();
+            match (gaps, t.pregap) {
+                (GapMode::Prepend, Some(gap)) if gap.file == start.file => gap,
+                _ => start,
+            }
+        };
+
+        self.tracks
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let start = begin(t);
+                let end = self.tracks.get(i + 1).and_then(|next| {
+                    let next = match gaps {
+                        // in the EAC layout, the pregap of the next track is at the end of this
+                        // file
+                        GapMode::Prepend => next.pregap.unwrap_or(begin(next)),
+                        GapMode::Append => begin(next),
+                    };
+                    (next.file == start.file).then_some(next.frames)
+                });
+                Segment {
+                    track: i,
+                    file: start.file,
+                    start: start.frames,
+                    end,
+                }
+            })
+            .collect()
+    }
+
+    /// Segments of each file containing more than one track, keyed by file
+    /// index. Other files need not be split.
+    fn image_segments(
+        &self,
+        gaps: GapMode,
+    ) -> Vec<(usize, Vec<Segment>)> {
+        (0..self.files.len())
+            .map(|f| {
+                let segments = self
+                    .segments(gaps)
+                    .into_iter()
+                    .filter(|s| s.file == f)
+                    .collect::<Vec<_>>();
+                (f, segments)
+            })
+            .filter(|(_, segments)| segments.len() > 1)
+            .collect()
+    }
+
+    /// Images referenced by the sheet (located at `path`), whether or not they
+    /// have been split yet.
+    pub fn images(
+        &self,
+        path: &Path,
+    ) -> Vec<PathBuf> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        self.image_segments(GapMode::Append)
+            .into_iter()
+            .map(|(f, _)| dir.join(&self.files[f]))
+            .collect()
+    }
+
+    /// Split every image referenced by the sheet (located at `path`) into one
+    /// FLAC per track, tagged with the sheet's metadata. Files that already
+    /// contain a single track are left alone, as are images whose tracks all
+    /// exist (i.e. split by a previous run). Existing files are never
+    /// overwritten.
+    ///
+    /// `flac` does the actual work, which limits images to FLAC and WAV.
+    pub fn split(
+        &self,
+        path: &Path,
+        gaps: GapMode,
+    ) -> Result<Vec<Split>> {
+        let dir = path.parent().context("no parent")?;
+        let mut splits = vec![];
+
+        for (f, segments) in self.image_segments(gaps) {
+            let image = dir.join(&self.files[f]);
+            let tracks: Vec<PathBuf> = segments
+                .iter()
+                .map(|s| dir.join(self.track_filename(&self.tracks[s.track])))
+                .collect();
+            if tracks.iter().all(|t| t.exists()) {
+                continue;
+            }
+            if let Some(t) = tracks.iter().find(|t| t.exists()) {
+                anyhow::bail!("partially split: {} already exists", t.display());
+            }
+
+            let sample_rate = lofty::read_from_path(&image)?
+                .properties()
+                .sample_rate()
+                .context("no sample rate")?;
+            // exact for 44.1 kHz, which is 588 samples per frame
+            let samples = |frames: u32| frames as u64 * sample_rate as u64 / FRAMES_PER_SEC as u64;
+
+            for (seg, out) in segments.iter().zip(&tracks) {
+                let track = &self.tracks[seg.track];
+                let mut cmd = Command::new("flac");
+                cmd.arg("--silent")
+                    .arg(format!("--skip={}", samples(seg.start)));
+                if let Some(end) = seg.end {
+                    cmd.arg(format!("--until={}", samples(end)));
+                }
+                for (field, val) in self.track_tags(track) {
+                    cmd.arg(format!("--tag={field}={val}"));
+                }
+                let status = cmd.arg("-o").arg(out).arg(&image).status()?;
+                if !status.success() {
+                    // so that the next run does not consider the image partially split
+                    for t in &tracks {
+                        let _ = fs::remove_file(t);
+                    }
+                    anyhow::bail!("could not split {}", image.display());
+                }
+            }
+
+            splits.push(Split {
+                image,
+                tracks,
+                skipped: segments[0].start as u64 * 1000 / FRAMES_PER_SEC as u64,
+            });
+        }
+
+        Ok(splits)
+    }
+
+    /// `01 - Title.flac`; `/` is not allowed in filenames, so it is replaced
+    fn track_filename(
+        &self,
+        track: &CueTrack,
+    ) -> String {
+        match &track.title {
+            Some(title) => format!("{:02} - {}.flac", track.number, title.replace('/', "-")),
+            None => format!("{:02}.flac", track.number),
+        }
+    }
+
+    /// Vorbis comments for one track
+    fn track_tags(
+        &self,
+        track: &CueTrack,
+    ) -> Vec<(&str, String)> {
+        let mut tags = vec![("TRACKNUMBER", track.number.to_string())];
+        let mut add = |field, val: Option<&String>| {
+            if let Some(val) = val {
+                tags.push((field, val.clone()));
+            }
+        };
+        add("TITLE", track.title.as_ref());
+        add(
+            "ARTIST",
+            track.performer.as_ref().or(self.performer.as_ref()),
+        );
+        add("ALBUMARTIST", self.performer.as_ref());
+        add("ALBUM", self.title.as_ref());
+        add("DATE", self.rem.get("DATE"));
+        add("GENRE", self.rem.get("GENRE"));
+        add("ISRC", track.isrc.as_ref());
+        add("BARCODE", self.barcode().map(|b| b.to_string()).as_ref());
+        add(
+            "CATALOGNUMBER",
+            self.catno().map(|c| c.to_string()).as_ref(),
+        );
+        tags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use crate::cue::CueIndex;
+    use crate::cue::CueSheet;
+    use crate::cue::GapMode;
+    use crate::cue::Segment;
+
+    const SINGLE: &str = r#"
+REM GENRE Jazz
+REM DATE 1959
+PERFORMER "Miles Davis"
+TITLE "Kind of Blue"
+CATALOG 5099706935121
+FILE "Kind of Blue.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "So What"
+    ISRC USSM15900113
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Freddie Freeloader"
+    INDEX 00 09:20:10
+    INDEX 01 09:22:00
+  TRACK 03 AUDIO
+    TITLE "Blue in Green"
+    PERFORMER "Miles Davis & Bill Evans"
+    INDEX 01 19:00:00
+"#;
+
+    // non-compliant EAC layout: the pregap of track 2 is at the end of file 1
+    const MULTI: &str = r#"
+PERFORMER "Foo"
+TITLE "Bar"
+CATALOG ABC-123
+FILE "01.wav" WAVE
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 00 04:00:00
+FILE "02.wav" WAVE
+    INDEX 01 00:00:00
+"#;
+
+    #[test]
+    fn test_parse_single() {
+        let cue = CueSheet::parse(SINGLE).unwrap();
+        assert_eq!(cue.title.as_deref(), Some("Kind of Blue"));
+        assert_eq!(cue.performer.as_deref(), Some("Miles Davis"));
+        assert_eq!(cue.rem.get("DATE").unwrap(), "1959");
+        assert_eq!(cue.files, vec!["Kind of Blue.flac"]);
+        assert_eq!(cue.tracks.len(), 3);
+        assert_eq!(cue.tracks[0].isrc.as_deref(), Some("USSM15900113"));
+        assert_eq!(
+            cue.tracks[1].pregap,
+            Some(CueIndex {
+                file: 0,
+                frames: (9 * 60 + 20) * 75 + 10
+            })
+        );
+        assert_eq!(cue.barcode(), Some("5099706935121"));
+        assert_eq!(cue.catno(), None);
+        assert!(cue.is_image());
+        assert_eq!(
+            cue.images(Path::new("/music/foo/Kind of Blue.cue")),
+            vec![PathBuf::from("/music/foo/Kind of Blue.flac")]
+        );
+    }
+
+    #[test]
+    fn test_segments() {
+        let cue = CueSheet::parse(SINGLE).unwrap();
+        let starts: Vec<u32> = cue
+            .segments(GapMode::Append)
+            .iter()
+            .map(|s| s.start)
+            .collect();
+        assert_eq!(starts, vec![0, 562 * 75, 1140 * 75]);
+        let seg = cue.segments(GapMode::Prepend);
+        assert_eq!(seg[0].end, Some(560 * 75 + 10));
+        assert_eq!(seg[1].start, 560 * 75 + 10);
+        assert_eq!(seg[2].end, None);
+    }
+
+    #[test]
+    fn test_multi_file() {
+        let cue = CueSheet::parse(MULTI).unwrap();
+        assert_eq!(cue.files, vec!["01.wav", "02.wav"]);
+        assert_eq!(cue.tracks[1].pregap.unwrap().file, 0);
+        assert_eq!(cue.tracks[1].start.unwrap().file, 1);
+        assert!(!cue.is_image());
+        assert!(cue.images(Path::new("foo.cue")).is_empty());
+        assert_eq!(cue.barcode(), None);
+        assert_eq!(cue.catno(), Some("ABC-123"));
+
+        assert_eq!(
+            cue.segments(GapMode::Append),
+            vec![
+                Segment {
+                    track: 0,
+                    file: 0,
+                    start: 0,
+                    end: None,
+                },
+                Segment {
+                    track: 1,
+                    file: 1,
+                    start: 0,
+                    end: None,
+                },
+            ]
+        );
+        // the pregap stays in the first file
+        assert_eq!(cue.segments(GapMode::Prepend)[0].end, Some(240 * 75));
+    }
+
+    #[test]
+    fn test_track_tags() {
+        let cue = CueSheet::parse(SINGLE).unwrap();
+        let tags = cue.track_tags(&cue.tracks[2]);
+        assert!(tags.contains(&("ARTIST", "Miles Davis & Bill Evans".to_string())));
+        assert!(tags.contains(&("ALBUMARTIST", "Miles Davis".to_string())));
+        assert!(tags.contains(&("TRACKNUMBER", "3".to_string())));
+        assert_eq!(cue.track_filename(&cue.tracks[0]), "01 - So What.flac");
+    }
+}
@@ -0,0 +1,56 @@
+//! Shared sqlite helpers. Schemas are versioned with `PRAGMA user_version`:
+//! migration `i` brings the schema to version `i + 1`, and is only ever applied
+//! once. Migrations must therefore never be edited after release, only
+//! appended to.
+
+use rusqlite::Connection;
+
+pub fn migrate(
+    conn: &mut Connection,
+    migrations: &[&str],
+) -> rusqlite::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in migrations.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Open (or create) the database at `db_path`, and bring it up to date.
+pub fn open(
+    db_path: &str,
+    migrations: &[&str],
+) -> rusqlite::Result<Connection> {
+    let mut conn = Connection::open(db_path)?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    migrate(&mut conn, migrations)?;
+    Ok(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use crate::db::migrate;
+
+    #[test]
+    fn test_migrate() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let v1 = ["create table foo (a integer);"];
+        migrate(&mut conn, &v1).unwrap();
+        migrate(&mut conn, &v1).unwrap(); // not applied twice
+
+        let v2 = [v1[0], "alter table foo add column b text;"];
+        migrate(&mut conn, &v2).unwrap();
+        conn.execute("insert into foo (a, b) values (1, 'x')", [])
+            .unwrap();
+
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 2);
+    }
+}
@@ -5,6 +5,7 @@ use reqwest::blocking::Response;
 use reqwest::header::AUTHORIZATION;
 use reqwest::header::CACHE_CONTROL;
 use reqwest::header::USER_AGENT;
+use reqwest::Method;
 use serde_json::Value;
 
 // https://www.discogs.com/developers/
@@ -58,6 +59,7 @@ impl Credentials {
 
 pub enum RequestType {
     Release,
+    Master,
     Artist,
     Label,
     Collection,
@@ -69,12 +71,24 @@ pub enum RequestType {
 pub fn make_request(
     request_type: RequestType,
     query: &str,
+) -> Result<Response, reqwest::Error> {
+    send(Method::GET, request_type, query, None)
+}
+
+/// Like `make_request`, but for any method. Write endpoints (POST/DELETE) take
+/// an optional JSON body. Note that the status is not checked.
+pub fn send(
+    method: Method,
+    request_type: RequestType,
+    query: &str,
+    body: Option<&Value>,
 ) -> Result<Response, reqwest::Error> {
     let mut creds = Credentials::build();
 
     let url_fragment = match request_type {
         RequestType::Collection => format!("/users/{}/{query}", creds.username),
         RequestType::Release => format!("/releases/{query}"),
+        RequestType::Master => format!("/masters/{query}"),
         RequestType::Search => query.to_string(),
         // artist, label
         _ => unimplemented!(),
@@ -84,12 +98,23 @@ pub fn make_request(
     creds.check_timestamps();
 
     let client = reqwest::blocking::Client::new();
-    client
-        .get(format!("{}{}", API_PREFIX, url_fragment))
+    let req = client
+        .request(method, format!("{}{}", API_PREFIX, url_fragment))
         .header(USER_AGENT, "Discogs client")
         .header(CACHE_CONTROL, "no-cache")
-        .header(AUTHORIZATION, format!("Discogs token={}", creds.token))
-        .send()
+        .header(AUTHORIZATION, format!("Discogs token={}", creds.token));
+    match body {
+        Some(body) => req.json(body).send(),
+        None => req.send(),
+    }
+}
+
+/// Percent-encode `params` as a query string (without the leading `?`), so
+/// that user input containing `&`, `#`, `+` etc. is passed through verbatim
+pub fn encode_query(params: &[(&str, &str)]) -> String {
+    let mut url = reqwest::Url::parse(API_PREFIX).expect("valid prefix");
+    url.query_pairs_mut().extend_pairs(params);
+    url.query().unwrap_or_default().to_string()
 }
 
 /// transform json response to serde Value
@@ -103,3 +128,17 @@ pub fn parse_json(resp: Response) -> Value {
 // fn test_request_count() {
 //     // not sure how to test this yet
 // }
+
+#[cfg(test)]
+mod tests {
+    use crate::http::encode_query;
+
+    #[test]
+    fn test_encode_query() {
+        assert_eq!(
+            encode_query(&[("catno", "A&M #1+2"), ("type", "release")]),
+            "catno=A%26M+%231%2B2&type=release"
+        );
+        assert_eq!(encode_query(&[]), "");
+    }
+}
@@ -0,0 +1,321 @@
+//! Configurable keys of the tagger (see the `[tagger.keys]` config section).
+//! Keys are looked up per mode, so the same key can be bound to different
+//! actions in the main view, alignment mode and editing mode.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use anyhow::Result;
+use crossterm::event::KeyCode;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Actions of every mode (see `Action::MAIN`, `Action::ALIGNMENT` and
+/// `Action::EDITING`). Text input (search, cell edits) has fixed keys.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Quit,
+    Down,
+    Up,
+    PageDown,
+    PageUp,
+    Top,
+    Bottom,
+    NextCandidate,
+    PreviousCandidate,
+    Pick,
+    Search,
+    Edit,
+    /// Switch between the directory list and the candidate list; `Down`,
+    /// `Up`, etc apply to the focused list
+    Focus,
+    /// Cycle through directory statuses to show only
+    Filter,
+    Help,
+    /// Leave alignment or editing mode
+    Back,
+    MoveDown,
+    MoveUp,
+    Ignore,
+    Apply,
+    Left,
+    Right,
+    Mark,
+    MarkAll,
+    EditCell,
+    Unstage,
+    Write,
+}
+
+impl Action {
+    pub const MAIN: [Action; 15] = [
+        Action::Quit,
+        Action::Down,
+        Action::Up,
+        Action::PageDown,
+        Action::PageUp,
+        Action::Top,
+        Action::Bottom,
+        Action::NextCandidate,
+        Action::PreviousCandidate,
+        Action::Pick,
+        Action::Search,
+        Action::Edit,
+        Action::Focus,
+        Action::Filter,
+        Action::Help,
+    ];
+
+    pub const ALIGNMENT: [Action; 7] = [
+        Action::Down,
+        Action::Up,
+        Action::MoveDown,
+        Action::MoveUp,
+        Action::Ignore,
+        Action::Apply,
+        Action::Back,
+    ];
+
+    /// `Mark` applies to the selected row, `MarkAll` to every row
+    pub const EDITING: [Action; 10] = [
+        Action::Down,
+        Action::Up,
+        Action::Right,
+        Action::Left,
+        Action::Mark,
+        Action::MarkAll,
+        Action::EditCell,
+        Action::Unstage,
+        Action::Write,
+        Action::Back,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Action::Quit => "quit",
+            Action::Down => "down",
+            Action::Up => "up",
+            Action::PageDown => "page down",
+            Action::PageUp => "page up",
+            Action::Top => "top",
+            Action::Bottom => "bottom",
+            Action::NextCandidate => "next candidate",
+            Action::PreviousCandidate => "previous candidate",
+            Action::Pick => "pick candidate",
+            Action::Search => "search",
+            Action::Edit => "edit tags",
+            Action::Focus => "switch panel",
+            Action::Filter => "filter by status",
+            Action::Help => "help",
+            Action::Back => "back",
+            Action::MoveDown => "move down",
+            Action::MoveUp => "move up",
+            Action::Ignore => "ignore",
+            Action::Apply => "apply",
+            Action::Left => "previous column",
+            Action::Right => "next column",
+            Action::Mark => "mark",
+            Action::MarkAll => "mark all",
+            Action::EditCell => "edit",
+            Action::Unstage => "unstage",
+            Action::Write => "write",
+        }
+    }
+
+    fn default_keys(&self) -> Vec<Key> {
+        use KeyCode::*;
+        let keys = match self {
+            Action::Quit => vec![Char('q'), Esc],
+            Action::Down => vec![Char('j'), Down],
+            Action::Up => vec![Char('k'), Up],
+            Action::PageDown => vec![Char('J'), PageDown],
+            Action::PageUp => vec![Char('K'), PageUp],
+            Action::Top => vec![Char('g'), Home],
+            Action::Bottom => vec![Char('G'), End],
+            Action::NextCandidate => vec![Char('l'), Right],
+            Action::PreviousCandidate => vec![Char('h'), Left],
+            Action::Pick => vec![Enter],
+            Action::Search => vec![Char('/')],
+            Action::Edit => vec![Char('e')],
+            Action::Focus => vec![Tab],
+            Action::Filter => vec![Char('f')],
+            Action::Help => vec![Char('?')],
+            Action::Back => vec![Esc, Char('q')],
+            Action::MoveDown => vec![Char('J')],
+            Action::MoveUp => vec![Char('K')],
+            Action::Ignore => vec![Char('x')],
+            Action::Apply => vec![Char('a')],
+            Action::Left => vec![Char('h'), Left],
+            Action::Right => vec![Char('l'), Right],
+            Action::Mark => vec![Char('v')],
+            Action::MarkAll => vec![Char('V')],
+            Action::EditCell => vec![Char('i'), Enter],
+            Action::Unstage => vec![Char('u')],
+            Action::Write => vec![Char('w')],
+        };
+        keys.into_iter().map(Key).collect()
+    }
+}
+
+/// Keys that are not single characters, as written in the config
+const KEY_NAMES: [(&str, KeyCode); 12] = [
+    ("Backspace", KeyCode::Backspace),
+    ("Delete", KeyCode::Delete),
+    ("Down", KeyCode::Down),
+    ("End", KeyCode::End),
+    ("Enter", KeyCode::Enter),
+    ("Esc", KeyCode::Esc),
+    ("Home", KeyCode::Home),
+    ("Left", KeyCode::Left),
+    ("PageDown", KeyCode::PageDown),
+    ("PageUp", KeyCode::PageUp),
+    ("Right", KeyCode::Right),
+    ("Tab", KeyCode::Tab),
+];
+
+/// A single key, e.g. `j`, `G`, `Enter` or `PageDown`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Key(KeyCode);
+
+impl TryFrom<String> for Key {
+    type Error = anyhow::Error;
+    fn try_from(s: String) -> Result<Self> {
+        if let Some((_, code)) = KEY_NAMES.iter().find(|(name, _)| *name == s) {
+            return Ok(Key(*code));
+        }
+        match (s.chars().next(), s.chars().count()) {
+            (Some(c), 1) => Ok(Key(KeyCode::Char(c))),
+            _ => Err(anyhow::anyhow!("invalid key: {s}")),
+        }
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> Self { key.to_string() }
+}
+
+impl Display for Key {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        match (self.0, KEY_NAMES.iter().find(|(_, code)| *code == self.0)) {
+            (KeyCode::Char(c), _) => write!(f, "{c}"),
+            (_, Some((name, _))) => write!(f, "<{}>", name.to_lowercase()),
+            (code, None) => write!(f, "{code:?}"),
+        }
+    }
+}
+
+/// The `[tagger]` section of the config. Keys for an action replace its
+/// default keys (see `Action::default_keys`), e.g.
+///
+/// ```toml
+/// [tagger.keys]
+/// down = ["n", "Down"]
+/// up = ["e", "Up"]
+/// move_down = ["N"]
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct TaggerConfig {
+    pub keys: HashMap<Action, Vec<Key>>,
+}
+
+impl TaggerConfig {
+    pub fn keys(
+        &self,
+        action: Action,
+    ) -> Vec<Key> {
+        match self.keys.get(&action) {
+            Some(keys) => keys.clone(),
+            None => action.default_keys(),
+        }
+    }
+
+    /// Only `actions` (usually those of the current mode) are considered. If a
+    /// key is bound to several of them, the first wins.
+    pub fn action(
+        &self,
+        code: KeyCode,
+        actions: &[Action],
+    ) -> Option<Action> {
+        actions
+            .iter()
+            .copied()
+            .find(|a| self.keys(*a).contains(&Key(code)))
+    }
+
+    /// e.g. `j/<down>`
+    pub fn describe(
+        &self,
+        action: Action,
+    ) -> String {
+        self.keys(action).iter().join("/")
+    }
+
+    /// e.g. `j/<down> down | k/<up> up`
+    pub fn summary(
+        &self,
+        actions: &[Action],
+        sep: &str,
+    ) -> String {
+        actions
+            .iter()
+            .map(|a| format!("{} {}", self.describe(*a), a.as_str()))
+            .join(sep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::KeyCode;
+
+    use crate::config::Config;
+    use crate::keymap::Action;
+
+    #[test]
+    fn test_keymap() {
+        let cfg = Config::parse(
+            r#"
+            [tagger.keys]
+            down = ["n", "Down"]
+            help = ["F"]
+            apply = ["Enter"]
+            "#,
+        )
+        .unwrap();
+        let keys = &cfg.tagger;
+        let main = &Action::MAIN;
+        assert_eq!(keys.action(KeyCode::Char('n'), main), Some(Action::Down));
+        assert_eq!(keys.action(KeyCode::Char('j'), main), None); // replaced
+        assert_eq!(keys.action(KeyCode::Char('g'), main), Some(Action::Top)); // default
+        assert_eq!(keys.action(KeyCode::Char('?'), main), None);
+        assert_eq!(keys.describe(Action::Down), "n/<down>");
+
+        // the same key means different things in each mode
+        let (align, edit) = (&Action::ALIGNMENT, &Action::EDITING);
+        assert_eq!(
+            keys.action(KeyCode::Char('J'), main),
+            Some(Action::PageDown)
+        );
+        assert_eq!(
+            keys.action(KeyCode::Char('J'), align),
+            Some(Action::MoveDown)
+        );
+        assert_eq!(keys.action(KeyCode::Enter, align), Some(Action::Apply));
+        assert_eq!(keys.action(KeyCode::Char('a'), align), None);
+        assert_eq!(keys.action(KeyCode::Enter, edit), Some(Action::EditCell));
+        assert_eq!(keys.action(KeyCode::Char('n'), edit), Some(Action::Down));
+        assert_eq!(
+            keys.summary(&[Action::Ignore, Action::Back], " | "),
+            "x ignore | <esc>/q back"
+        );
+
+        assert!(Config::parse("[tagger.keys]\ndown = [\"Foo\"]").is_err());
+        assert!(Config::parse("[tagger.keys]\nfoo = [\"j\"]").is_err());
+    }
+}
@@ -1,9 +1,19 @@
+pub mod browser;
 pub mod cli;
 pub mod collection;
+pub mod config;
+pub mod cue;
+pub mod db;
 pub mod http;
 pub mod io;
+pub mod keymap;
 pub mod lastfm;
+pub mod loudness;
+pub mod query;
+pub mod reconcile;
 pub mod release;
 pub mod search;
 pub mod tagger;
 pub mod transcode;
+pub mod verify;
+pub mod wantlist;
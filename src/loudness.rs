@@ -0,0 +1,238 @@
+//! Loudness analysis (EBU R128), written as ReplayGain 2.0 and R128 tags.
+//!
+//! The measurement itself is done by ffmpeg's `ebur128` filter; we only parse
+//! its summary. Pure-Rust alternatives exist (`ebur128` + `symphonia`), but
+//! would require decoding every format ourselves.
+
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::Context;
+use anyhow::Result;
+use id3::frame::ExtendedText;
+use id3::TagLike;
+use lofty::AudioFile;
+
+/// ReplayGain 2.0 reference level, in LUFS
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+/// EBU R128 reference level, in LUFS
+const R128_REFERENCE: f64 = -23.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness, in LUFS
+    pub integrated: f64,
+    /// True peak, in dBTP
+    pub peak: f64,
+    /// In seconds; only used to weight tracks when computing album loudness
+    pub duration: f64,
+}
+
+impl Loudness {
+    pub fn scan(path: &str) -> Result<Self> {
+        let out = Command::new("ffmpeg")
+            .args(["-hide_banner", "-nostats", "-i", path])
+            .args(["-filter_complex", "ebur128=peak=true", "-f", "null", "-"])
+            .stdin(Stdio::null())
+            .output()?;
+        anyhow::ensure!(out.status.success(), "ffmpeg failed on {path}");
+
+        let duration = lofty::read_from_path(Path::new(path))?
+            .properties()
+            .duration()
+            .as_secs_f64();
+
+        Self::parse(&String::from_utf8_lossy(&out.stderr), duration)
+    }
+
+    /// Only the summary (printed at the very end) is parsed:
+    ///
+    /// ```text
+    /// [Parsed_ebur128_0 @ 0x55d0c1e0] Summary:
+    ///
+    ///   Integrated loudness:
+    ///     I:         -17.6 LUFS
+    ///     Threshold: -28.0 LUFS
+    ///   ...
+    ///   True peak:
+    ///     Peak:       -0.5 dBFS
+    /// ```
+    fn parse(
+        stderr: &str,
+        duration: f64,
+    ) -> Result<Self> {
+        let summary = stderr
+            .rsplit_once("Summary:")
+            .context("no ebur128 summary")?
+            .1;
+        let value = |key: &str| -> Result<f64> {
+            let val = summary
+                .lines()
+                .find_map(|l| l.trim().strip_prefix(key))
+                .with_context(|| format!("no {key} in summary"))?
+                .split_whitespace()
+                .next()
+                .context("empty value")?;
+            Ok(val.parse()?)
+        };
+        Ok(Self {
+            integrated: value("I:")?,
+            peak: value("Peak:")?,
+            duration,
+        })
+    }
+
+    /// Album loudness is approximated as the duration-weighted mean of track
+    /// loudness (in the power domain, not in dB). This ignores the gating
+    /// across track boundaries that a true album scan would do, but is usually
+    /// within a few tenths of a dB.
+    pub fn album(tracks: &[Loudness]) -> Option<Self> {
+        let total: f64 = tracks.iter().map(|t| t.duration).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let power: f64 = tracks
+            .iter()
+            .map(|t| t.duration * 10_f64.powf(t.integrated / 10.0))
+            .sum();
+        Some(Self {
+            integrated: 10.0 * (power / total).log10(),
+            peak: tracks.iter().map(|t| t.peak).fold(f64::MIN, f64::max),
+            duration: total,
+        })
+    }
+
+    /// In dB
+    pub fn replaygain(&self) -> f64 { REPLAYGAIN_REFERENCE - self.integrated }
+
+    /// As specified by RFC 7845: a Q7.8 fixed point number, relative to
+    /// -23 LUFS.
+    pub fn r128_gain(&self) -> i16 {
+        ((R128_REFERENCE - self.integrated) * 256.0)
+            .round()
+            .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    }
+
+    /// Linear amplitude, where 1.0 is full scale
+    pub fn peak_ratio(&self) -> f64 { 10_f64.powf(self.peak / 20.0) }
+}
+
+/// Descriptions of the TXXX frames written by `write_tags`; also used as vorbis
+/// comment names
+pub const FIELDS: [&str; 6] = [
+    "REPLAYGAIN_TRACK_GAIN",
+    "REPLAYGAIN_TRACK_PEAK",
+    "R128_TRACK_GAIN",
+    "REPLAYGAIN_ALBUM_GAIN",
+    "REPLAYGAIN_ALBUM_PEAK",
+    "R128_ALBUM_GAIN",
+];
+
+/// Write track (and album, if available) gain as TXXX frames. Existing values
+/// are replaced.
+pub fn write_tags(
+    tags: &mut id3::Tag,
+    track: &Loudness,
+    album: Option<&Loudness>,
+) {
+    let mut set = |description: &str, value: String| {
+        tags.add_frame(ExtendedText {
+            description: description.to_string(),
+            value,
+        });
+    };
+
+    set(
+        "REPLAYGAIN_TRACK_GAIN",
+        format!("{:.2} dB", track.replaygain()),
+    );
+    set(
+        "REPLAYGAIN_TRACK_PEAK",
+        format!("{:.6}", track.peak_ratio()),
+    );
+    set("R128_TRACK_GAIN", track.r128_gain().to_string());
+
+    if let Some(album) = album {
+        set(
+            "REPLAYGAIN_ALBUM_GAIN",
+            format!("{:.2} dB", album.replaygain()),
+        );
+        set(
+            "REPLAYGAIN_ALBUM_PEAK",
+            format!("{:.6}", album.peak_ratio()),
+        );
+        set("R128_ALBUM_GAIN", album.r128_gain().to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loudness::write_tags;
+    use crate::loudness::Loudness;
+    use crate::loudness::FIELDS;
+
+    const SUMMARY: &str = "
+[Parsed_ebur128_0 @ 0x55d0c1e0] t: 1.2       TARGET:-23 LUFS    M: -20.1 S:-120.7     I: -20.1 LUFS
+[Parsed_ebur128_0 @ 0x55d0c1e0] Summary:
+
+  Integrated loudness:
+    I:         -17.6 LUFS
+    Threshold: -28.0 LUFS
+
+  Loudness range:
+    LRA:         6.3 LU
+    Threshold: -38.0 LUFS
+    LRA low:   -22.4 LUFS
+    LRA high:  -16.1 LUFS
+
+  True peak:
+    Peak:       -0.5 dBFS
+";
+
+    #[test]
+    fn test_parse_summary() {
+        let l = Loudness::parse(SUMMARY, 100.0).unwrap();
+        assert_eq!(l.integrated, -17.6);
+        assert_eq!(l.peak, -0.5);
+        assert_eq!(format!("{:.2}", l.replaygain()), "-0.40");
+        assert_eq!(l.r128_gain(), -1382); // -5.4 * 256
+
+        assert!(Loudness::parse("no summary", 0.0).is_err());
+    }
+
+    #[test]
+    fn test_album() {
+        let track = |integrated, peak, duration| Loudness {
+            integrated,
+            peak,
+            duration,
+        };
+        // identical tracks produce identical album loudness
+        let album =
+            Loudness::album(&[track(-14.0, -1.0, 60.0), track(-14.0, -0.2, 120.0)]).unwrap();
+        assert!((album.integrated + 14.0).abs() < 1e-9);
+        assert_eq!(album.peak, -0.2);
+
+        // a long quiet track dominates a short loud one
+        let album = Loudness::album(&[track(-10.0, 0.0, 10.0), track(-30.0, 0.0, 1000.0)]).unwrap();
+        assert!(album.integrated < -25.0);
+
+        assert_eq!(Loudness::album(&[]), None);
+    }
+
+    #[test]
+    fn test_write_tags() {
+        let mut tags = id3::Tag::new();
+        let l = Loudness::parse(SUMMARY, 100.0).unwrap();
+        write_tags(&mut tags, &l, None);
+        write_tags(&mut tags, &l, Some(&l)); // existing frames are replaced
+        assert_eq!(tags.extended_texts().count(), 6);
+        assert!(tags
+            .extended_texts()
+            .all(|t| FIELDS.contains(&t.description.as_str())));
+        assert!(tags
+            .extended_texts()
+            .any(|t| t.description == "REPLAYGAIN_TRACK_GAIN" && t.value == "-0.40 dB"));
+    }
+}
@@ -0,0 +1,557 @@
+//! Filtering and statistics over the (synced) collection. Everything is done
+//! in memory, as even a large collection is only a few thousand instances.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Write;
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::collection::CollectionResult;
+use crate::reconcile::OutputFormat;
+
+/// Inclusive numeric bounds, parsed from `1960`, `1955..1965`, `1955..`,
+/// `..1965`, `>=4`, `>3`, `<=2`, `<2` or `=5`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Bounds {
+    pub min: Option<usize>,
+    pub max: Option<usize>,
+}
+
+impl Bounds {
+    pub fn contains(
+        &self,
+        n: usize,
+    ) -> bool {
+        self.min.unwrap_or(0) <= n && n <= self.max.unwrap_or(usize::MAX)
+    }
+}
+
+impl std::str::FromStr for Bounds {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let num = |n: &str| -> Result<usize> { Ok(n.trim().parse()?) };
+        let opt = |n: &str| -> Result<Option<usize>> {
+            match n.trim() {
+                "" => Ok(None),
+                n => Ok(Some(num(n)?)),
+            }
+        };
+
+        let bounds = if let Some((lo, hi)) = s.split_once("..") {
+            Bounds {
+                min: opt(lo)?,
+                max: opt(hi)?,
+            }
+        } else if let Some(n) = s.strip_prefix(">=") {
+            Bounds {
+                min: Some(num(n)?),
+                max: None,
+            }
+        } else if let Some(n) = s.strip_prefix("<=") {
+            Bounds {
+                min: None,
+                max: Some(num(n)?),
+            }
+        } else if let Some(n) = s.strip_prefix('>') {
+            Bounds {
+                min: Some(num(n)? + 1),
+                max: None,
+            }
+        } else if let Some(n) = s.strip_prefix('<') {
+            Bounds {
+                min: None,
+                max: Some(num(n)?.saturating_sub(1)),
+            }
+        } else {
+            let n = num(s.strip_prefix('=').unwrap_or(s))?;
+            Bounds {
+                min: Some(n),
+                max: Some(n),
+            }
+        };
+        Ok(bounds)
+    }
+}
+
+/// All conditions must hold. String matches are case-insensitive; genre,
+/// style and format must match exactly, artist, title and label only
+/// partially.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct Filter {
+    #[arg(long)]
+    pub artist: Option<String>,
+    #[arg(long)]
+    pub title: Option<String>,
+    #[arg(long)]
+    pub genre: Option<String>,
+    #[arg(long)]
+    pub style: Option<String>,
+    #[arg(long)]
+    pub label: Option<String>,
+    /// e.g. `Vinyl`, `CD`
+    #[arg(long)]
+    pub format: Option<String>,
+    #[arg(long)]
+    pub country: Option<String>,
+    #[arg(long)]
+    pub folder: Option<usize>,
+    /// e.g. `1960`, `1955..1965`, `>=1990`
+    #[arg(long)]
+    pub year: Option<Bounds>,
+    /// e.g. `5`, `>=4`; unrated is 0
+    #[arg(long)]
+    pub rating: Option<Bounds>,
+}
+
+impl Filter {
+    pub fn matches(
+        &self,
+        r: &CollectionResult,
+    ) -> bool {
+        let info = &r.basic_information;
+        let exact = |wanted: &Option<String>, mut values: std::slice::Iter<String>| {
+            wanted
+                .as_ref()
+                .is_none_or(|w| values.any(|v| v.eq_ignore_ascii_case(w)))
+        };
+        let partial = |wanted: &Option<String>, values: &[&str]| {
+            wanted.as_ref().is_none_or(|w| {
+                let w = w.to_lowercase();
+                values.iter().any(|v| v.to_lowercase().contains(&w))
+            })
+        };
+
+        exact(&self.genre, info.genres.iter())
+            && exact(&self.style, info.styles.iter())
+            && self
+                .format
+                .as_ref()
+                .is_none_or(|w| info.formats.iter().any(|f| f.name.eq_ignore_ascii_case(w)))
+            && self.country.as_ref().is_none_or(|w| {
+                info.country
+                    .as_ref()
+                    .is_some_and(|c| c.eq_ignore_ascii_case(w))
+            })
+            && partial(
+                &self.artist,
+                &info.artists.iter().map(|a| a.name.as_str()).collect_vec(),
+            )
+            && partial(&self.title, &[info.title.as_str()])
+            && partial(
+                &self.label,
+                &info.labels.iter().map(|l| l.name.as_str()).collect_vec(),
+            )
+            && self.folder.is_none_or(|f| f == r.folder_id)
+            && self.year.is_none_or(|y| y.contains(info.year))
+            && self.rating.is_none_or(|b| b.contains(r.rating as usize))
+    }
+
+    pub fn apply<'a>(
+        &self,
+        releases: &'a [CollectionResult],
+    ) -> Vec<&'a CollectionResult> {
+        releases.iter().filter(|r| self.matches(r)).collect()
+    }
+}
+
+/// Flattened `CollectionResult`, one per instance
+#[derive(Serialize, Debug, PartialEq)]
+pub struct QueryRow {
+    pub instance_id: usize,
+    pub release_id: usize,
+    pub artist: String,
+    pub title: String,
+    pub year: usize,
+    pub rating: u8,
+    pub folder_id: usize,
+    pub genres: String,
+    pub styles: String,
+    pub labels: String,
+    pub formats: String,
+    pub date_added: String,
+}
+
+impl From<&CollectionResult> for QueryRow {
+    fn from(r: &CollectionResult) -> Self {
+        let info = &r.basic_information;
+        Self {
+            instance_id: r.instance_id,
+            release_id: r.id,
+            artist: info.artists.iter().map(|a| a.name.as_str()).join(", "),
+            title: info.title.clone(),
+            year: info.year,
+            rating: r.rating,
+            folder_id: r.folder_id,
+            genres: info.genres.join(", "),
+            styles: info.styles.join(", "),
+            labels: info.labels.iter().map(|l| l.name.as_str()).join(", "),
+            formats: info.formats.iter().map(|f| f.name.as_str()).join(", "),
+            date_added: r.date_added.clone(),
+        }
+    }
+}
+
+/// Columns are padded to the widest cell (in chars)
+fn write_table(
+    mut out: impl Write,
+    header: &[&str],
+    rows: &[Vec<String>],
+) -> Result<()> {
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|r| r[i].chars().count())
+                .chain([header[i].len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let line = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{c:w$}"))
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    writeln!(out, "{}", line(header.to_vec()))?;
+    for row in rows {
+        writeln!(out, "{}", line(row.iter().map(|c| c.as_str()).collect()))?;
+    }
+    Ok(())
+}
+
+/// Without a format, a table is printed.
+pub fn write_rows(
+    rows: &[QueryRow],
+    format: Option<OutputFormat>,
+    out: impl Write,
+) -> Result<()> {
+    match format {
+        Some(OutputFormat::Csv) => {
+            let mut w = csv::Writer::from_writer(out);
+            for row in rows {
+                w.serialize(row)?;
+            }
+            w.flush()?;
+        }
+        Some(OutputFormat::Json) => serde_json::to_writer_pretty(out, rows)?,
+        None => {
+            let cells = rows
+                .iter()
+                .map(|r| {
+                    vec![
+                        r.release_id.to_string(),
+                        r.artist.clone(),
+                        r.title.clone(),
+                        r.year.to_string(),
+                        r.rating.to_string(),
+                        r.formats.clone(),
+                    ]
+                })
+                .collect_vec();
+            write_table(
+                out,
+                &["id", "artist", "title", "year", "rating", "format"],
+                &cells,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Breakdown {
+    Genre,
+    Style,
+    Decade,
+    Label,
+    Format,
+    Country,
+    Rating,
+    Artist,
+}
+
+impl Breakdown {
+    pub const ALL: [Breakdown; 8] = [
+        Breakdown::Genre,
+        Breakdown::Style,
+        Breakdown::Decade,
+        Breakdown::Label,
+        Breakdown::Format,
+        Breakdown::Country,
+        Breakdown::Rating,
+        Breakdown::Artist,
+    ];
+
+    fn as_str(&self) -> &str {
+        match self {
+            Breakdown::Genre => "genre",
+            Breakdown::Style => "style",
+            Breakdown::Decade => "decade",
+            Breakdown::Label => "label",
+            Breakdown::Format => "format",
+            Breakdown::Country => "country",
+            Breakdown::Rating => "rating",
+            Breakdown::Artist => "artist",
+        }
+    }
+
+    /// Distinct values of an instance; multi-valued fields (genres, etc) count
+    /// once per value.
+    fn values(
+        &self,
+        r: &CollectionResult,
+    ) -> HashSet<String> {
+        let info = &r.basic_information;
+        match self {
+            Breakdown::Genre => info.genres.iter().cloned().collect(),
+            Breakdown::Style => info.styles.iter().cloned().collect(),
+            Breakdown::Decade => HashSet::from([match info.year {
+                0 => "unknown".to_string(),
+                y => format!("{}s", y / 10 * 10),
+            }]),
+            Breakdown::Label => info.labels.iter().map(|l| l.name.clone()).collect(),
+            Breakdown::Format => info.formats.iter().map(|f| f.name.clone()).collect(),
+            Breakdown::Country => HashSet::from([info
+                .country
+                .clone()
+                .filter(|c| !c.is_empty())
+                .unwrap_or("unknown".to_string())]),
+            Breakdown::Rating => HashSet::from([r.rating.to_string()]),
+            Breakdown::Artist => info.artists.iter().map(|a| a.name.clone()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Count {
+    pub value: String,
+    pub count: usize,
+}
+
+/// Sorted by count (descending), then value
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub counts: Vec<Count>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Stats {
+    pub total: usize,
+    pub breakdowns: Vec<Section>,
+}
+
+impl Stats {
+    /// Only the `top` most common values of each breakdown are kept, except
+    /// for decade and rating, which are short and more useful in full.
+    pub fn new(
+        releases: &[&CollectionResult],
+        top: usize,
+    ) -> Self {
+        let breakdowns = Breakdown::ALL
+            .iter()
+            .map(|b| {
+                let mut counts: HashMap<String, usize> = HashMap::new();
+                for r in releases {
+                    for v in b.values(r) {
+                        *counts.entry(v).or_default() += 1;
+                    }
+                }
+                let counts = counts
+                    .into_iter()
+                    .map(|(value, count)| Count { value, count })
+                    .sorted_by(|a, b| b.count.cmp(&a.count).then(a.value.cmp(&b.value)));
+                let counts = match b {
+                    Breakdown::Decade | Breakdown::Rating => {
+                        counts.sorted_by(|a, b| a.value.cmp(&b.value)).collect()
+                    }
+                    _ => counts.take(top).collect(),
+                };
+                Section {
+                    name: b.as_str().to_string(),
+                    counts,
+                }
+            })
+            .collect();
+        Self {
+            total: releases.len(),
+            breakdowns,
+        }
+    }
+
+    /// CSV rows are `breakdown,value,count`. Without a format, one table per
+    /// breakdown is printed.
+    pub fn write(
+        &self,
+        format: Option<OutputFormat>,
+        mut out: impl Write,
+    ) -> Result<()> {
+        match format {
+            Some(OutputFormat::Csv) => {
+                let mut w = csv::Writer::from_writer(out);
+                w.write_record(["breakdown", "value", "count"])?;
+                for section in &self.breakdowns {
+                    for c in &section.counts {
+                        w.write_record([&section.name, &c.value, &c.count.to_string()])?;
+                    }
+                }
+                w.flush()?;
+            }
+            Some(OutputFormat::Json) => serde_json::to_writer_pretty(out, self)?,
+            None => {
+                writeln!(out, "{} instance(s)", self.total)?;
+                for section in &self.breakdowns {
+                    writeln!(out)?;
+                    let cells = section
+                        .counts
+                        .iter()
+                        .map(|c| vec![c.value.clone(), c.count.to_string()])
+                        .collect_vec();
+                    write_table(&mut out, &[&section.name, "count"], &cells)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collection::test_instance;
+    use crate::collection::CollectionResult;
+    use crate::query::Bounds;
+    use crate::query::Filter;
+    use crate::query::Stats;
+    use crate::release::Format;
+
+    fn instance(
+        id: usize,
+        year: usize,
+        rating: u8,
+        genres: &[&str],
+    ) -> CollectionResult {
+        let mut r = test_instance(id, id, "Miles Davis");
+        r.rating = rating;
+        let info = &mut r.basic_information;
+        info.title = format!("Album {id}");
+        info.year = year;
+        info.genres = genres.iter().map(|g| g.to_string()).collect();
+        info.formats = vec![Format {
+            name: "Vinyl".to_string(),
+            qty: "1".to_string(),
+            descriptions: vec![],
+            text: None,
+        }];
+        r
+    }
+
+    #[test]
+    fn test_bounds() {
+        let b = |s: &str| s.parse::<Bounds>().unwrap();
+        assert_eq!(
+            b("1960"),
+            Bounds {
+                min: Some(1960),
+                max: Some(1960)
+            }
+        );
+        assert_eq!(
+            b("1955..1965"),
+            Bounds {
+                min: Some(1955),
+                max: Some(1965)
+            }
+        );
+        assert_eq!(
+            b("..1965"),
+            Bounds {
+                min: None,
+                max: Some(1965)
+            }
+        );
+        assert_eq!(
+            b(">=4"),
+            Bounds {
+                min: Some(4),
+                max: None
+            }
+        );
+        assert_eq!(
+            b(">3"),
+            Bounds {
+                min: Some(4),
+                max: None
+            }
+        );
+        assert_eq!(
+            b("<1"),
+            Bounds {
+                min: None,
+                max: Some(0)
+            }
+        );
+        assert!(b("1955..1965").contains(1965));
+        assert!(!b("1955..1965").contains(1966));
+        assert!("foo".parse::<Bounds>().is_err());
+    }
+
+    #[test]
+    fn test_filter_and_stats() {
+        let coll = [
+            instance(1, 1959, 5, &["Jazz"]),
+            instance(2, 1970, 4, &["Jazz", "Funk / Soul"]),
+            instance(3, 1959, 0, &["Jazz"]),
+            instance(4, 1986, 3, &["Rock"]),
+        ];
+
+        let filter = Filter {
+            genre: Some("jazz".to_string()),
+            year: Some("1955..1965".parse().unwrap()),
+            rating: Some(">=4".parse().unwrap()),
+            ..Default::default()
+        };
+        let found = filter.apply(&coll);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, 1);
+
+        let artist = Filter {
+            artist: Some("davis".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(artist.apply(&coll).len(), 4);
+
+        let stats = Stats::new(&coll.iter().collect::<Vec<_>>(), 1);
+        assert_eq!(stats.total, 4);
+        let genres = &stats.breakdowns[0];
+        assert_eq!(genres.name, "genre");
+        assert_eq!(genres.counts.len(), 1); // top 1 only
+        assert_eq!(
+            (genres.counts[0].value.as_str(), genres.counts[0].count),
+            ("Jazz", 3)
+        );
+        assert_eq!(
+            stats.breakdowns[2]
+                .counts
+                .iter()
+                .map(|c| c.value.as_str())
+                .collect::<Vec<_>>(),
+            ["1950s", "1970s", "1980s"]
+        );
+
+        let mut csv = vec![];
+        stats
+            .write(Some(crate::reconcile::OutputFormat::Csv), &mut csv)
+            .unwrap();
+        assert!(String::from_utf8(csv)
+            .unwrap()
+            .starts_with("breakdown,value,count\ngenre,Jazz,3\n"));
+    }
+}
@@ -0,0 +1,416 @@
+//! Compare the Discogs collection (what we own) with the library (what we
+//! have ripped).
+//!
+//! Library albums tagged with a Discogs release ID (see `LibraryDB::deep_scan`)
+//! are matched exactly; the rest are matched fuzzily on artist and title, with
+//! the year only used to break ties. Matching is one-to-one, so two pressings
+//! of the same album in the collection require two albums in the library.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::Write;
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::Serialize;
+use strsim::jaro_winkler;
+
+use crate::collection::CollectionResult;
+use crate::io::LibraryEntry;
+
+/// Minimum Jaro-Winkler similarity of both artist and title
+pub const FUZZY_THRESHOLD: f64 = 0.9;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Discrepancy {
+    OwnedNotRipped,
+    RippedNotOwned,
+    /// Matched, but year or title differ
+    Mismatch,
+}
+
+impl Discrepancy {
+    fn as_str(&self) -> &str {
+        match self {
+            Discrepancy::OwnedNotRipped => "owned_not_ripped",
+            Discrepancy::RippedNotOwned => "ripped_not_owned",
+            Discrepancy::Mismatch => "mismatch",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Row {
+    pub kind: Discrepancy,
+    pub release_id: Option<usize>,
+    /// Relative to the library root
+    pub path: Option<String>,
+    pub artist: String,
+    pub title: String,
+    pub year: Option<usize>,
+    pub detail: Option<String>,
+}
+
+impl Display for Row {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        write!(
+            f,
+            "[{}] {} - {}",
+            self.kind.as_str(),
+            self.artist,
+            self.title
+        )?;
+        if let Some(year) = self.year {
+            write!(f, " ({year})")?;
+        }
+        if let Some(id) = self.release_id {
+            write!(f, " [{id}]")?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, ": {detail}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => anyhow::bail!("invalid output format: {s}"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    /// Number of matched pairs, including mismatches
+    pub matched: usize,
+    pub rows: Vec<Row>,
+    /// Instance ID and library path of every matched pair
+    #[serde(skip)]
+    pub pairs: Vec<(usize, String)>,
+}
+
+impl Report {
+    /// Without a format, rows are printed one per line, followed by a summary.
+    pub fn write(
+        &self,
+        format: Option<OutputFormat>,
+        mut out: impl Write,
+    ) -> Result<()> {
+        match format {
+            Some(OutputFormat::Csv) => {
+                let mut w = csv::Writer::from_writer(out);
+                for row in &self.rows {
+                    w.serialize(row)?;
+                }
+                w.flush()?;
+            }
+            Some(OutputFormat::Json) => serde_json::to_writer_pretty(out, self)?,
+            None => {
+                for row in &self.rows {
+                    writeln!(out, "{row}")?;
+                }
+                let count = |kind| self.rows.iter().filter(|r| r.kind == kind).count();
+                writeln!(
+                    out,
+                    "{} matched, {} mismatched, {} owned but not ripped, {} ripped but not owned",
+                    self.matched,
+                    count(Discrepancy::Mismatch),
+                    count(Discrepancy::OwnedNotRipped),
+                    count(Discrepancy::RippedNotOwned),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase alphanumerics only, without a leading article or the numeric
+/// suffix Discogs uses to disambiguate artists (e.g. `Foo (2)`).
+pub fn normalize(s: &str) -> String {
+    let s = match s.trim().rsplit_once(" (") {
+        Some((name, suffix))
+            if suffix
+                .strip_suffix(')')
+                .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())) =>
+        {
+            name
+        }
+        _ => s,
+    };
+    let s = s
+        .to_lowercase()
+        .chars()
+        .map(|c| match c.is_alphanumeric() {
+            true => c,
+            false => ' ',
+        })
+        .collect::<String>();
+    let s = s.split_whitespace().join(" ");
+    match s.strip_prefix("the ") {
+        Some(stripped) => stripped.to_string(),
+        None => s,
+    }
+}
+
+fn release_artist(r: &CollectionResult) -> &str {
+    r.basic_information
+        .artists
+        .first()
+        .map(|a| a.name.as_str())
+        .unwrap_or_default()
+}
+
+fn owned_not_ripped(r: &CollectionResult) -> Row {
+    Row {
+        kind: Discrepancy::OwnedNotRipped,
+        release_id: Some(r.id),
+        path: None,
+        artist: release_artist(r).to_string(),
+        title: r.basic_information.title.clone(),
+        year: Some(r.basic_information.year).filter(|y| *y != 0),
+        detail: None,
+    }
+}
+
+fn ripped_not_owned(
+    album: &LibraryEntry,
+    release_id: Option<usize>,
+) -> Row {
+    Row {
+        kind: Discrepancy::RippedNotOwned,
+        release_id,
+        path: Some(album.path.clone()),
+        artist: album.artist.clone(),
+        title: album.album.clone(),
+        year: Some(album.year),
+        detail: None,
+    }
+}
+
+/// None if the pair agrees. A year of 0 means unknown (to Discogs).
+fn mismatch(
+    album: &LibraryEntry,
+    release: &CollectionResult,
+) -> Option<Row> {
+    let info = &release.basic_information;
+    let mut details = vec![];
+    if info.year != 0 && info.year != album.year {
+        details.push(format!("year {} != {}", album.year, info.year));
+    }
+    if normalize(&album.album) != normalize(&info.title) {
+        details.push(format!("title {:?} != {:?}", album.album, info.title));
+    }
+    if details.is_empty() {
+        return None;
+    }
+    Some(Row {
+        kind: Discrepancy::Mismatch,
+        release_id: Some(release.id),
+        path: Some(album.path.clone()),
+        artist: album.artist.clone(),
+        title: album.album.clone(),
+        year: Some(album.year),
+        detail: Some(details.join(", ")),
+    })
+}
+
+/// `release_ids` maps library paths to the Discogs release ID found in their
+/// tags.
+pub fn reconcile(
+    collection: &[CollectionResult],
+    library: &[LibraryEntry],
+    release_ids: &HashMap<String, usize>,
+) -> Report {
+    let mut report = Report::default();
+    // keyed by instance, as the same release may be owned more than once
+    let mut unmatched: HashMap<usize, &CollectionResult> =
+        collection.iter().map(|r| (r.instance_id, r)).collect();
+    let mut instances: HashMap<usize, Vec<usize>> = collection
+        .iter()
+        .map(|r| (r.id, r.instance_id))
+        .into_group_map();
+    let mut pairs = vec![];
+    let mut untagged = vec![];
+
+    for album in library {
+        let Some(id) = release_ids.get(&album.path) else {
+            untagged.push(album);
+            continue;
+        };
+        let instance = instances.get_mut(id).and_then(|i| i.pop());
+        match instance.and_then(|i| unmatched.remove(&i)) {
+            Some(release) => pairs.push((album, release)),
+            None => report.rows.push(ripped_not_owned(album, Some(*id))),
+        }
+    }
+
+    // instances by normalized artist, so that titles are only compared within an artist
+    let mut by_artist: HashMap<String, Vec<usize>> = unmatched
+        .values()
+        .sorted_by_key(|r| r.instance_id)
+        .map(|r| (normalize(release_artist(r)), r.instance_id))
+        .into_group_map();
+    let mut similar_artists: HashMap<String, Vec<String>> = HashMap::new();
+
+    for album in untagged {
+        let artist = normalize(&album.artist);
+        let title = normalize(&album.album);
+        let candidates = similar_artists.entry(artist.clone()).or_insert_with(|| {
+            by_artist
+                .keys()
+                .filter(|a| jaro_winkler(a, &artist) >= FUZZY_THRESHOLD)
+                .cloned()
+                .collect()
+        });
+
+        let best = candidates
+            .iter()
+            .flat_map(|a| by_artist[a].iter().map(move |i| (a, *i)))
+            .filter_map(|(a, i)| {
+                let release = unmatched.get(&i)?;
+                let title_sim = jaro_winkler(&normalize(&release.basic_information.title), &title);
+                if title_sim < FUZZY_THRESHOLD {
+                    return None;
+                }
+                let year_bonus = match release.basic_information.year == album.year {
+                    true => 0.1,
+                    false => 0.0,
+                };
+                Some((jaro_winkler(a, &artist) + title_sim + year_bonus, a, i))
+            })
+            .max_by(|x, y| x.0.total_cmp(&y.0));
+
+        match best {
+            Some((_, a, i)) => {
+                by_artist.get_mut(a).unwrap().retain(|j| *j != i);
+                pairs.push((album, unmatched.remove(&i).unwrap()));
+            }
+            None => report.rows.push(ripped_not_owned(album, None)),
+        }
+    }
+
+    report.matched = pairs.len();
+    report.pairs = pairs
+        .iter()
+        .map(|(a, r)| (r.instance_id, a.path.clone()))
+        .collect();
+    report
+        .rows
+        .extend(pairs.into_iter().filter_map(|(a, r)| mismatch(a, r)));
+    report.rows.extend(
+        unmatched
+            .into_values()
+            .sorted_by_key(|r| (r.id, r.instance_id))
+            .map(owned_not_ripped),
+    );
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::collection::test_instance;
+    use crate::collection::CollectionResult;
+    use crate::io::LibraryEntry;
+    use crate::reconcile::normalize;
+    use crate::reconcile::reconcile;
+    use crate::reconcile::Discrepancy;
+    use crate::reconcile::OutputFormat;
+
+    fn release(
+        id: usize,
+        artist: &str,
+        title: &str,
+        year: usize,
+    ) -> CollectionResult {
+        let mut r = test_instance(id * 10, id, artist);
+        r.basic_information.title = title.to_string();
+        r.basic_information.year = year;
+        r
+    }
+
+    fn album(
+        artist: &str,
+        title: &str,
+        year: usize,
+    ) -> LibraryEntry {
+        LibraryEntry {
+            path: format!("{artist}/{title} ({year})"),
+            artist: artist.to_string(),
+            album: title.to_string(),
+            year,
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("The Beatles"), "beatles");
+        assert_eq!(normalize("Kino (2)"), "kino");
+        assert_eq!(normalize("Sunn O)))"), "sunn o");
+        assert_eq!(
+            normalize("Abbey Road (Remastered)"),
+            "abbey road remastered"
+        );
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let collection = [
+            release(1, "The Beatles", "Abbey Road", 1969),
+            release(2, "Kino (2)", "Gruppa Krovi", 1988),
+            release(3, "Slint", "Spiderland", 1991),
+            release(4, "Talk Talk", "Laughing Stock", 1991),
+        ];
+        let library = [
+            album("Beatles", "Abbey Road", 1969),       // fuzzy
+            album("Kino", "Группа крови", 1989),        // tagged, but mismatched
+            album("Slint", "Spiderland", 1991),         // tagged, correct
+            album("Bark Psychosis", "Hex", 1994),       // not owned
+            album("Talk Talk", "Spirit of Eden", 1988), // not owned
+        ];
+        let ids = HashMap::from([(library[1].path.clone(), 2), (library[2].path.clone(), 3)]);
+
+        let report = reconcile(&collection, &library, &ids);
+        assert_eq!(report.matched, 3);
+
+        let kinds: Vec<(Discrepancy, Option<usize>)> =
+            report.rows.iter().map(|r| (r.kind, r.release_id)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (Discrepancy::RippedNotOwned, None),
+                (Discrepancy::RippedNotOwned, None),
+                (Discrepancy::Mismatch, Some(2)),
+                (Discrepancy::OwnedNotRipped, Some(4)),
+            ]
+        );
+        assert!(report.rows[2]
+            .detail
+            .as_ref()
+            .unwrap()
+            .contains("year 1989 != 1988"));
+
+        let mut csv = vec![];
+        report.write(Some(OutputFormat::Csv), &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("kind,release_id,path,artist,title,year,detail\n"));
+        assert!(csv.contains("owned_not_ripped,4,,Talk Talk,Laughing Stock,1991,\n"));
+    }
+}
@@ -8,7 +8,7 @@ use serde::Serialize;
 use crate::http;
 use crate::search::SearchResults;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 enum TrackType {
     // TODO: rename_all
     #[serde(rename = "index")]
@@ -19,7 +19,7 @@ enum TrackType {
     Heading,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Track {
     pub title: String,
     /// May be an empty string (not None)
@@ -49,14 +49,29 @@ impl Display for Track {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Label {
     pub id: usize,
     pub name: String,
+    /// Catalog number; may be `none`
+    #[serde(default)]
+    pub catno: String,
     // other fields not implemented yet
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// e.g. `2 x Vinyl, LP, Album`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Format {
+    pub name: String,
+    /// A string, for some reason
+    pub qty: String,
+    #[serde(default)]
+    pub descriptions: Vec<String>,
+    /// Free text, e.g. `180g`
+    pub text: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Artist {
     // TODO: empty strings should be deserialised to None
     // https://docs.rs/serde_with/latest/serde_with/struct.NoneAsEmptyString.html
@@ -106,7 +121,7 @@ pub struct Master {
 ///
 /// Discogs does not provide a way to tell whether a release is primary; only
 /// master releases have this information.
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Release {
     // Explicitly defining multiple structs allows us to know all available fields at compile time,
     // providing a more ergonomic experience for callers (less Option checking), at the expense of
@@ -117,6 +132,9 @@ pub struct Release {
 
     /// Unique identifier of a Release.
     pub id: usize, // u32 is probably fine
+    /// 0 if the release has no master, as in `SearchRelease`
+    #[serde(default)]
+    pub master_id: usize,
     /// The earliest possible year is somewhere in the 1920s.
     pub year: u16,
 
@@ -147,7 +165,8 @@ pub struct Release {
     tracklist: Vec<Track>,
 
     // companies: Vec,
-    // formats: Vec,
+    #[serde(default)]
+    pub formats: Vec<Format>,
     // identifiers: Vec,
     /// The currency is assumed from the locale and not specified in the
     /// response.
@@ -160,12 +179,12 @@ impl Release {
     /// Note: passing a master ID will produce incorrect release! Use
     /// Release::get_master instead.
     ///
-    /// Returns None if release is not found.
+    /// Returns None if release is not found, or could not be fetched.
     pub fn get(release_id: usize) -> Option<Self> {
         let resp = http::make_request(http::RequestType::Release, &release_id.to_string()).ok()?;
 
         match resp.status() {
-            reqwest::StatusCode::OK => serde_json::from_str(resp.text().unwrap().as_str()).unwrap(),
+            reqwest::StatusCode::OK => serde_json::from_str(&resp.text().ok()?).ok(),
             _ => None,
         }
     }
@@ -177,30 +196,53 @@ impl Release {
         let resp = http::make_request(http::RequestType::Master, &master_id.to_string()).ok()?;
 
         match resp.status() {
-            reqwest::StatusCode::OK => serde_json::from_str(resp.text().unwrap().as_str()).unwrap(),
+            reqwest::StatusCode::OK => serde_json::from_str(&resp.text().ok()?).ok(),
             _ => None,
         }
     }
 
-    /// 50 per page. Returns None if no search results. Filtering is not handled
-    /// here.
+    /// 50 per page. Filtering is not handled here.
     pub fn search(
         artist: &str,
         album: &str,
         // ) -> Option<Vec<SearchRelease>> {
-    ) -> SearchResults {
+    ) -> Result<SearchResults> {
         // None is used over empty Vec as it better signals intent
-        let resp = http::make_request(
-            http::RequestType::Search,
-            &format!("/database/search?release_title={album}&artist={artist}&type=release"),
-        )
-        .unwrap();
-        let results: SearchResults = serde_json::from_str(resp.text().unwrap().as_str()).unwrap();
-        results
+        Self::search_query(&[("release_title", album), ("artist", artist)])
         // cast empty vec into None -- https://stackoverflow.com/a/65012849
         // (!results.results.is_empty()).then_some(results.results)
     }
 
+    /// Barcodes (UPC/EAN) usually identify a single release, or at most a
+    /// handful of represses.
+    pub fn search_barcode(barcode: &str) -> Result<SearchResults> {
+        Self::search_query(&[("barcode", barcode)])
+    }
+
+    /// Catalog numbers are not unique across labels, so results should be
+    /// checked.
+    pub fn search_catno(catno: &str) -> Result<SearchResults> {
+        Self::search_query(&[("catno", catno)])
+    }
+
+    /// `params` are encoded, and should not include `type`. Errors on any
+    /// non-OK status (e.g. 429, when rate limited).
+    fn search_query(params: &[(&str, &str)]) -> Result<SearchResults> {
+        let resp = http::make_request(
+            http::RequestType::Search,
+            &format!(
+                "/database/search?{}",
+                http::encode_query(&[params, &[("type", "release")]].concat())
+            ),
+        )?;
+        anyhow::ensure!(
+            resp.status() == reqwest::StatusCode::OK,
+            "search failed: {}",
+            resp.status()
+        );
+        Ok(serde_json::from_str(resp.text()?.as_str())?)
+    }
+
     pub fn durations(&self) -> Vec<u32> {
         fn as_int(dur: &str) -> Result<u32> {
             let mut int: u32 = 0;
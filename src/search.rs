@@ -2,7 +2,10 @@ use std::fmt::Display;
 
 use serde::Deserialize;
 use serde::Serialize;
+use strsim::jaro_winkler;
 
+use crate::reconcile::normalize;
+use crate::reconcile::FUZZY_THRESHOLD;
 use crate::release::Release;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -25,15 +28,15 @@ pub struct Page {
 /// absence of tracklist.
 pub struct SearchRelease {
     r#type: String,
-    catno: String,
-    country: String,
+    pub catno: String,
+    pub country: String,
     cover_image: String,
     format_quantity: usize,
     genre: Vec<String>,
     pub id: usize,
     pub label: Vec<String>, // should be renamed to labels
     /// May be 0, which means it has no master.
-    master_id: usize,
+    pub master_id: usize,
     master_url: Option<String>,
     resource_url: String,
     style: Vec<String>,
@@ -43,7 +46,7 @@ pub struct SearchRelease {
     // this makes sorting very annoying
     pub year: Option<String>,
     // barcode: Vec [],
-    format: Vec<String>,
+    pub format: Vec<String>,
     // formats: Vec ,
     // user_data: Object {
     //     in_collection: Bool,
@@ -56,6 +59,21 @@ pub struct SearchRelease {
 
 impl SearchRelease {
     pub fn as_rel(&self) -> Release { Release::get(self.id).unwrap() }
+
+    /// Whether both artist and album are similar (see `reconcile::normalize`).
+    /// Search results are titled `<artist> - <album>`; anything else never
+    /// matches.
+    pub fn matches(
+        &self,
+        artist: &str,
+        album: &str,
+    ) -> bool {
+        let Some((a, t)) = self.title.split_once(" - ") else {
+            return false;
+        };
+        jaro_winkler(&normalize(a), &normalize(artist)) >= FUZZY_THRESHOLD
+            && jaro_winkler(&normalize(t), &normalize(album)) >= FUZZY_THRESHOLD
+    }
 }
 
 impl Display for SearchRelease {
@@ -89,7 +107,7 @@ impl SearchResults {
     pub fn find_primary(&self) -> Option<Release> {
         for res in &self.results {
             if res.master_id > 0 {
-                let m = Release::get_master(res.master_id).unwrap();
+                let m = Release::get_master(res.master_id)?;
                 return Release::get(m.main_release);
             }
         }
@@ -134,12 +152,32 @@ impl Display for SearchResults {
 #[cfg(test)]
 mod tests {
     use crate::release::Release;
+    use crate::search::SearchRelease;
+
+    #[test]
+    fn test_matches() {
+        let result: SearchRelease = serde_json::from_str(
+            r#"{
+                "type": "release", "catno": "", "country": "US", "cover_image": "",
+                "format_quantity": 1, "genre": [], "id": 1, "label": [], "master_id": 2,
+                "master_url": null, "resource_url": "", "style": [], "thumb": "",
+                "title": "Metallica (2) - Ride The Lightning", "uri": "", "year": "1984",
+                "format": []
+            }"#,
+        )
+        .unwrap();
+        assert!(result.matches("Metallica", "Ride the Lightning"));
+        assert!(result.matches("metallica", "Ride The Lightning (Remastered)"));
+        assert!(!result.matches("Metallica", "Master of Puppets"));
+        assert!(!result.matches("Megadeth", "Ride the Lightning"));
+    }
+
     #[test]
     fn test_big_search() {
         let album = "ride the lightning";
         let artist = "metallica";
 
-        let search = Release::search(artist, album);
+        let search = Release::search(artist, album).unwrap();
         assert_eq!(search.results.len(), 50);
         // assert_eq!(search.results.first().unwrap().id, 1722463);
 
@@ -160,6 +198,6 @@ mod tests {
     fn test_empty_search() {
         let album = "djsakldjsakl";
         let artist = "metallica";
-        assert_eq!(Release::search(artist, album).results.len(), 0);
+        assert_eq!(Release::search(artist, album).unwrap().results.len(), 0);
     }
 }
@@ -1,8 +1,19 @@
 //! TUI implementation for tagging files in the source directory. Heavily
 //! borrowed from the [ratatui list example](https://docs.rs/ratatui/latest/src/list/list.rs.html).
 
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Display;
 use std::io;
 use std::io::stdout;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
 
 use anyhow::Context;
 use anyhow::Result;
@@ -19,22 +30,885 @@ use id3::TagLike;
 use itertools::Itertools;
 use ratatui::prelude::*;
 use ratatui::widgets::*;
+use strsim::jaro_winkler;
 use walkdir::DirEntry;
+use walkdir::WalkDir;
 
+use crate::config::CONFIG;
+use crate::cue::CueSheet;
 use crate::io::Walk;
 use crate::io::SOURCE;
+use crate::keymap::Action;
+use crate::reconcile::normalize;
+use crate::reconcile::FUZZY_THRESHOLD;
 use crate::release::Release;
+use crate::search::SearchRelease;
 use crate::transcode::File;
 use crate::transcode::SourceDir;
 use crate::transcode::TagField;
+use crate::transcode::TranscodeAction;
+
+/// Only the first few search results are worth considering; the rest are
+/// usually represses, or different releases altogether.
+const MAX_CANDIDATES: usize = 10;
+
+/// Percentage of tracks whose (rounded) duration is within 5 seconds of the
+/// Discogs duration, out of the larger of the two track counts. Tracks without
+/// a Discogs duration never match.
+fn match_score(
+    local: &[Option<u32>],
+    discogs: &[u32],
+) -> u8 {
+    let total = local.len().max(discogs.len());
+    if total == 0 {
+        return 0;
+    }
+    let matched = local
+        .iter()
+        .zip(discogs)
+        .filter(|(a, b)| **b > 0 && a.is_some_and(|a| a.abs_diff(**b) <= 5))
+        .count();
+    (matched * 100 / total) as u8
+}
+
+/// What to look up on Discogs for a directory. Parsed from user input as
+/// follows:
+///
+/// - `barcode:<code>`, `catno:<catno>`
+/// - a release or master URL, e.g. `https://www.discogs.com/master/123-foo`
+/// - `r:<id>` (release), `m:<id>` (master)
+/// - `<artist> - <album>`, or just `<album>` (which may be numeric, e.g. `1999`)
+#[derive(Debug, Clone, PartialEq)]
+enum Query {
+    Search {
+        artist: String,
+        album: String,
+    },
+    Release(usize),
+    /// Resolved to the main release of the master
+    Master(usize),
+    Barcode(String),
+    Catno(String),
+}
+
+impl FromStr for Query {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(code) = s.strip_prefix("barcode:") {
+            return Ok(Query::Barcode(code.trim().to_string()));
+        }
+        if let Some(catno) = s.strip_prefix("catno:") {
+            return Ok(Query::Catno(catno.trim().to_string()));
+        }
+        if s.contains("discogs.com/") {
+            // https://www.discogs.com/release/123-Artist-Title, or
+            // https://www.discogs.com/sell/release/123
+            let mut parts = s
+                .split('/')
+                .skip_while(|p| *p != "release" && *p != "master");
+            let kind = parts.next().context("not a release or master URL")?;
+            let id: usize = parts
+                .next()
+                .and_then(|p| p.split('-').next())
+                .context("no ID in URL")?
+                .parse()?;
+            return Ok(match kind {
+                "master" => Query::Master(id),
+                _ => Query::Release(id),
+            });
+        }
+        if let Some(id) = s.strip_prefix("r:") {
+            return Ok(Query::Release(id.trim().parse()?));
+        }
+        if let Some(id) = s.strip_prefix("m:") {
+            return Ok(Query::Master(id.trim().parse()?));
+        }
+        Ok(match s.split_once(" - ") {
+            Some((artist, album)) => Query::Search {
+                artist: artist.trim().to_string(),
+                album: album.trim().to_string(),
+            },
+            None => Query::Search {
+                artist: String::new(),
+                album: s.to_string(),
+            },
+        })
+    }
+}
+
+impl Display for Query {
+    /// The inverse of `from_str`
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        match self {
+            Query::Search { artist, album } if artist.is_empty() => write!(f, "{album}"),
+            Query::Search { artist, album } => write!(f, "{artist} - {album}"),
+            Query::Release(id) => write!(f, "r:{id}"),
+            Query::Master(id) => write!(f, "m:{id}"),
+            Query::Barcode(code) => write!(f, "barcode:{code}"),
+            Query::Catno(catno) => write!(f, "catno:{catno}"),
+        }
+    }
+}
+
+impl Query {
+    /// Initial query, from the tags of `file`
+    fn from_tags(file: &File) -> Self {
+        Query::Search {
+            artist: file.get(TagField::Artist).unwrap_or_default(),
+            album: file.get(TagField::Album).unwrap_or_default(),
+        }
+    }
+
+    /// Initial query, from the barcode (or catalog number) carried over from a
+    /// cue sheet into the tags of `file` (see `transcode::CATALOG_FIELDS`)
+    fn from_catalog(file: &File) -> Option<Self> {
+        match (file.extended("BARCODE"), file.extended("CATALOGNUMBER")) {
+            (Some(barcode), _) => Some(Query::Barcode(barcode.to_string())),
+            (None, Some(catno)) => Some(Query::Catno(catno.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Initial query, from the barcode (or catalog number) of the first cue
+    /// sheet in `dir` that has one. Sheets are usually archived once split, so
+    /// see also `from_catalog`.
+    fn from_cue(dir: &str) -> Option<Self> {
+        WalkDir::new(dir)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                e.path()
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+            })
+            .filter_map(|e| CueSheet::load(e.path()).ok())
+            .find_map(|cue| match (cue.barcode(), cue.catno()) {
+                (Some(barcode), _) => Some(Query::Barcode(barcode.to_string())),
+                (None, Some(catno)) => Some(Query::Catno(catno.to_string())),
+                _ => None,
+            })
+    }
+
+    /// Searches return at most `MAX_CANDIDATES`; releases and masters return
+    /// at most one.
+    fn fetch(&self) -> Result<Vec<Candidate>> {
+        let results = match self {
+            Query::Search { artist, album } if artist.is_empty() && album.is_empty() => {
+                return Ok(vec![])
+            }
+            Query::Search { artist, album } => Release::search(artist, album)?,
+            Query::Barcode(code) => Release::search_barcode(code)?,
+            Query::Catno(catno) => Release::search_catno(catno)?,
+            Query::Release(id) => {
+                return Ok(Release::get(*id).map(Candidate::from).into_iter().collect())
+            }
+            Query::Master(id) => {
+                return Ok(Release::get_master(*id)
+                    .and_then(|m| Release::get(m.main_release))
+                    .map(Candidate::from)
+                    .into_iter()
+                    .collect())
+            }
+        };
+        Ok(results
+            .results
+            .iter()
+            .take(MAX_CANDIDATES)
+            .map(Candidate::from)
+            .collect())
+    }
+}
+
+/// A possible release for a directory. Search results lack the tracklist, so
+/// the full release is only fetched when the candidate is first shown.
+struct Candidate {
+    id: usize,
+    /// Year, format, label, catalog number and country
+    summary: String,
+    release: Option<Release>,
+    /// The release could not be fetched
+    failed: bool,
+}
+
+impl From<&SearchRelease> for Candidate {
+    fn from(res: &SearchRelease) -> Self {
+        Self {
+            id: res.id,
+            summary: format!(
+                "{} {} {} {} {}",
+                res.year.as_deref().unwrap_or("????"),
+                res.format.join(", "),
+                res.label.first().map(|l| l.as_str()).unwrap_or("No label"),
+                res.catno,
+                res.country,
+            ),
+            release: None,
+            failed: false,
+        }
+    }
+}
+
+impl From<Release> for Candidate {
+    fn from(rel: Release) -> Self {
+        let label = rel.labels.first();
+        Self {
+            id: rel.id,
+            summary: format!(
+                "{} {} {} {} {}",
+                rel.year,
+                rel.formats
+                    .iter()
+                    .flat_map(|f| std::iter::once(&f.name).chain(&f.descriptions))
+                    .join(", "),
+                label.map(|l| l.name.as_str()).unwrap_or("No label"),
+                label.map(|l| l.catno.as_str()).unwrap_or_default(),
+                rel.country.as_deref().unwrap_or_default(),
+            ),
+            release: Some(rel),
+            failed: false,
+        }
+    }
+}
+
+/// Candidates for a single directory, fetched once when the directory is first
+/// selected, and again whenever the query is changed.
+struct Candidates {
+    /// Index (in `TaggerApp.items`) of the directory searched for
+    dir: usize,
+    query: Query,
+    list: Vec<Candidate>,
+    /// Durations of the local files, in seconds
+    durations: Vec<Option<u32>>,
+    current: usize,
+    picked: Option<usize>,
+}
+
+impl Candidates {
+    /// None until the first release is fetched
+    fn ambiguous(&self) -> Option<bool> {
+        if self.list.is_empty() {
+            return Some(true);
+        }
+        Some(self.score(0)? < 100)
+    }
+
+    fn score(
+        &self,
+        i: usize,
+    ) -> Option<u8> {
+        let rel = self.list.get(i)?.release.as_ref()?;
+        Some(match_score(&self.durations, &rel.durations()))
+    }
+
+    /// Allows wrap-around
+    fn next(&mut self) {
+        if !self.list.is_empty() {
+            self.current = (self.current + 1) % self.list.len();
+        }
+    }
+
+    fn previous(&mut self) {
+        if !self.list.is_empty() {
+            self.current = (self.current + self.list.len() - 1) % self.list.len();
+        }
+    }
+}
+
+/// A local file, as shown in the alignment view
+struct LocalTrack {
+    path: String,
+    title: String,
+    /// Seconds (rounded)
+    duration: Option<u32>,
+    /// Ignored files are neither paired with a Discogs track, nor tagged
+    ignored: bool,
+}
+
+/// Local files paired with the tracklist of the picked candidate by position.
+/// Files can be moved or ignored (e.g. hidden tracks) until the two line up.
+struct Alignment {
+    tracks: Vec<LocalTrack>,
+    state: TableState,
+}
+
+impl Alignment {
+    fn new(dir: &SourceDir) -> Self {
+        let tracks = dir
+            .files()
+            .into_iter()
+            .map(|f| LocalTrack {
+                title: f.get(TagField::Title).unwrap_or_default(),
+                duration: f.properties().map(|p| ((p.duration + 500) / 1000) as u32),
+                path: f.path,
+                ignored: false,
+            })
+            .collect();
+        Self {
+            tracks,
+            state: TableState::default().with_selected(Some(0)),
+        }
+    }
+
+    /// Allows wrap-around
+    fn select(
+        &mut self,
+        down: bool,
+    ) {
+        let len = self.tracks.len();
+        if let (Some(curr), true) = (self.state.selected(), len > 0) {
+            self.state.select(Some(match down {
+                true => (curr + 1) % len,
+                false => (curr + len - 1) % len,
+            }));
+        }
+    }
+
+    /// Swap the selected file with its neighbour; the selection follows the
+    /// file.
+    fn shift(
+        &mut self,
+        down: bool,
+    ) {
+        let Some(curr) = self.state.selected() else {
+            return;
+        };
+        let other = match down {
+            true if curr + 1 < self.tracks.len() => curr + 1,
+            false if curr > 0 => curr - 1,
+            _ => return,
+        };
+        self.tracks.swap(curr, other);
+        self.state.select(Some(other));
+    }
+
+    fn toggle_ignored(&mut self) {
+        if let Some(t) = self.state.selected().and_then(|i| self.tracks.get_mut(i)) {
+            t.ignored = !t.ignored;
+        }
+    }
+
+    /// Files to tag, in tracklist order
+    fn paths(&self) -> impl Iterator<Item = &str> {
+        self.tracks
+            .iter()
+            .filter(|t| !t.ignored)
+            .map(|t| t.path.as_str())
+    }
+}
+
+/// Used to filter the directory list. A directory can have several statuses,
+/// e.g. untagged and needing transcoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Untagged,
+    /// Every file has a Discogs release ID
+    Tagged,
+    /// Some file would be transcoded by the configured policy
+    Transcode,
+    /// Untagged, and the first candidate does not fully match
+    Ambiguous,
+    Error,
+}
+
+impl Status {
+    const ALL: [Status; 5] = [
+        Status::Untagged,
+        Status::Tagged,
+        Status::Transcode,
+        Status::Ambiguous,
+        Status::Error,
+    ];
+
+    fn as_str(&self) -> &str {
+        match self {
+            Status::Untagged => "untagged",
+            Status::Tagged => "tagged",
+            Status::Transcode => "needs transcoding",
+            Status::Ambiguous => "ambiguous",
+            Status::Error => "error",
+        }
+    }
+}
+
+/// Everything but ambiguity, which depends on the candidates. Computed by
+/// `status_worker`, since every file has to be read.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct DirStatus {
+    tagged: bool,
+    transcode: bool,
+    error: Option<String>,
+}
+
+/// Audio files of the directory at `path` (see `SourceDir::files`). A
+/// directory without any is an error.
+fn read_audio(path: &str) -> Result<Vec<File>> {
+    let files = SourceDir::new(path)?.files();
+    if files.is_empty() {
+        anyhow::bail!("no audio files in {path}");
+    }
+    Ok(files)
+}
+
+/// New position in a list of `len` items, moving `step` items. Moves stop at
+/// either end of the list, and only wrap around from there. None if the list
+/// is empty.
+fn step_index(
+    curr: usize,
+    len: usize,
+    step: usize,
+    down: bool,
+) -> Option<usize> {
+    let last = len.checked_sub(1)?;
+    Some(match down {
+        true if curr >= last => 0,
+        true => (curr + step).min(last),
+        false if curr == 0 => last,
+        false => curr.saturating_sub(step),
+    })
+}
+
+impl DirStatus {
+    fn new(path: &str) -> Self {
+        let files = match read_audio(path) {
+            Ok(files) => files,
+            Err(e) => {
+                return Self {
+                    error: Some(format!("{e:#}")),
+                    ..Default::default()
+                }
+            }
+        };
+        Self {
+            tagged: files
+                .iter()
+                .all(|f| f.get(TagField::DiscogsReleaseId).is_some()),
+            transcode: files.iter().any(|f| {
+                matches!(
+                    f.transcode_action(&CONFIG.transcode),
+                    TranscodeAction::Transcode(_)
+                )
+            }),
+            error: None,
+        }
+    }
+
+    /// `ambiguous` is None if not known yet
+    fn has(
+        &self,
+        status: Status,
+        ambiguous: Option<bool>,
+    ) -> bool {
+        match status {
+            Status::Untagged => !self.tagged && self.error.is_none(),
+            Status::Tagged => self.tagged,
+            Status::Transcode => self.transcode,
+            Status::Ambiguous => !self.tagged && self.error.is_none() && ambiguous == Some(true),
+            Status::Error => self.error.is_some(),
+        }
+    }
+
+    /// Two characters: `*` (tagged), `?` (ambiguous), `-` (untagged) or `!`
+    /// (error, or `failed` to fetch candidates), then `~` if the directory
+    /// needs transcoding
+    fn markers(
+        &self,
+        ambiguous: Option<bool>,
+        failed: bool,
+    ) -> Span<'static> {
+        let transcode = match self.transcode {
+            true => "~",
+            false => " ",
+        };
+        if self.error.is_some() || failed {
+            Span::from(format!("!{transcode}")).red()
+        } else if self.tagged {
+            Span::from(format!("*{transcode}")).green()
+        } else if ambiguous == Some(true) {
+            Span::from(format!("?{transcode}")).yellow()
+        } else {
+            Span::from(format!("-{transcode}"))
+        }
+    }
+}
+
+/// Staged tag edits for the selected directory, like a small spreadsheet: one
+/// row per file, one column per `TagField`. Nothing is written until `save`.
+struct TagEditor {
+    files: Vec<File>,
+    /// (row, field) -> new value
+    staged: HashMap<(usize, TagField), String>,
+    state: TableState,
+    col: usize,
+    /// Rows to edit at once; if empty, only the selected row is edited
+    marked: BTreeSet<usize>,
+    /// Value being typed; None when not editing a cell
+    input: Option<String>,
+    error: Option<String>,
+}
+
+impl TagEditor {
+    fn new(files: Vec<File>) -> Self {
+        Self {
+            files,
+            staged: HashMap::new(),
+            state: TableState::default().with_selected(Some(0)),
+            col: 0,
+            marked: BTreeSet::new(),
+            input: None,
+            error: None,
+        }
+    }
+
+    fn field(&self) -> TagField { TagField::ALL[self.col] }
+
+    /// Staged value, if any, otherwise the current value
+    fn value(
+        &self,
+        row: usize,
+        field: TagField,
+    ) -> String {
+        match self.staged.get(&(row, field)) {
+            Some(v) => v.clone(),
+            None => self.files[row].get(field).unwrap_or_default(),
+        }
+    }
+
+    fn rows(&self) -> Vec<usize> {
+        match (self.marked.is_empty(), self.state.selected()) {
+            (false, _) => self.marked.iter().copied().collect(),
+            (true, Some(row)) if row < self.files.len() => vec![row],
+            _ => vec![],
+        }
+    }
+
+    /// Allows wrap-around
+    fn select(
+        &mut self,
+        down: bool,
+    ) {
+        let len = self.files.len();
+        if let (Some(curr), true) = (self.state.selected(), len > 0) {
+            self.state.select(Some(match down {
+                true => (curr + 1) % len,
+                false => (curr + len - 1) % len,
+            }));
+        }
+    }
+
+    fn select_col(
+        &mut self,
+        right: bool,
+    ) {
+        let len = TagField::ALL.len();
+        self.col = match right {
+            true => (self.col + 1) % len,
+            false => (self.col + len - 1) % len,
+        };
+    }
+
+    fn toggle_mark(&mut self) {
+        let Some(row) = self.state.selected() else {
+            return;
+        };
+        if !self.marked.remove(&row) {
+            self.marked.insert(row);
+        }
+    }
+
+    /// Mark all rows, or unmark all if all are already marked
+    fn toggle_all(&mut self) {
+        match self.marked.len() == self.files.len() {
+            true => self.marked.clear(),
+            false => self.marked = (0..self.files.len()).collect(),
+        }
+    }
+
+    /// Stage `value` for the selected field of every row to edit
+    fn stage(
+        &mut self,
+        value: &str,
+    ) -> Result<()> {
+        let field = self.field();
+        field.validate(value)?;
+        for row in self.rows() {
+            self.staged.insert((row, field), value.to_string());
+        }
+        Ok(())
+    }
+
+    fn unstage(&mut self) {
+        let field = self.field();
+        for row in self.rows() {
+            self.staged.remove(&(row, field));
+        }
+    }
+
+    /// Write all staged values. Only modified files are written.
+    fn save(&mut self) -> Result<()> {
+        let rows: BTreeSet<usize> = self.staged.keys().map(|(row, _)| *row).collect();
+        for ((row, field), value) in self.staged.iter() {
+            self.files[*row].set(*field, value);
+        }
+        for row in rows {
+            self.files[row].write_tags()?;
+        }
+        self.staged.clear();
+        Ok(())
+    }
+}
+
+/// Number of directories after the selected one to fetch candidates for
+const PREFETCH: usize = 3;
+
+/// Identifies requests in flight (see `Request::key`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RequestKey {
+    /// Directory and generation (see `TaggerApp::generations`)
+    Candidates(usize, usize),
+    /// Directory and release ID
+    Release(usize, usize),
+    Apply(usize),
+}
+
+/// Network (and other slow) work, done by `worker`
+enum Request {
+    /// Search for the directory at `path`, and fetch the first release. If
+    /// `query` is None, a cue sheet (see `Query::from_cue`) is tried first,
+    /// then the barcode or catalog number in the tags of the first file, then
+    /// its artist and album.
+    Candidates {
+        dir: usize,
+        generation: usize,
+        path: String,
+        query: Option<Query>,
+    },
+    Release {
+        dir: usize,
+        id: usize,
+    },
+    /// Tag `files` (in tracklist order) with `release`; see
+    /// `SourceDir::apply_discogs_to`
+    Apply {
+        dir: usize,
+        path: String,
+        release: Box<Release>,
+        files: Vec<String>,
+    },
+}
+
+enum Response {
+    Candidates {
+        generation: usize,
+        candidates: Candidates,
+    },
+    Status {
+        dir: usize,
+        status: DirStatus,
+    },
+    Release {
+        dir: usize,
+        id: usize,
+        /// Boxed, since `Release` is much larger than `Candidates`
+        release: Option<Box<Release>>,
+    },
+    Applied {
+        dir: usize,
+    },
+    /// The request with this key failed
+    Error {
+        key: RequestKey,
+        error: String,
+    },
+}
+
+impl Request {
+    fn key(&self) -> RequestKey {
+        match self {
+            Request::Candidates {
+                dir, generation, ..
+            } => RequestKey::Candidates(*dir, *generation),
+            Request::Release { dir, id } => RequestKey::Release(*dir, *id),
+            Request::Apply { dir, .. } => RequestKey::Apply(*dir),
+        }
+    }
+
+    /// Errors (network, rate limiting, unexpected responses) are returned as
+    /// `Response::Error`, so that the request is no longer pending.
+    fn handle(self) -> Response {
+        let key = self.key();
+        self.fetch().unwrap_or_else(|e| Response::Error {
+            key,
+            error: format!("{e:#}"),
+        })
+    }
+
+    fn fetch(self) -> Result<Response> {
+        Ok(match self {
+            Request::Candidates {
+                dir,
+                generation,
+                path,
+                query,
+            } => {
+                let source = SourceDir::new(&path).ok();
+                let queries = match query {
+                    Some(query) => vec![query],
+                    // a barcode is more specific than tags, but may not be on Discogs
+                    None => {
+                        let first = source.as_ref().and_then(|d| d.files().into_iter().next());
+                        Query::from_cue(&path)
+                            .or_else(|| Query::from_catalog(first.as_ref()?))
+                            .into_iter()
+                            .chain(first.as_ref().map(Query::from_tags))
+                            .collect()
+                    }
+                };
+                let mut query = Query::Search {
+                    artist: String::new(),
+                    album: String::new(),
+                };
+                let mut list = vec![];
+                for q in queries {
+                    list = q.fetch()?;
+                    query = q;
+                    if !list.is_empty() {
+                        break;
+                    }
+                }
+                if let Some(first) = list.first_mut().filter(|c| c.release.is_none()) {
+                    first.release = Release::get(first.id);
+                    first.failed = first.release.is_none();
+                }
+                Response::Candidates {
+                    generation,
+                    candidates: Candidates {
+                        dir,
+                        list,
+                        query,
+                        durations: source.map(|d| d.durations()).unwrap_or_default(),
+                        current: 0,
+                        picked: None,
+                    },
+                }
+            }
+            Request::Release { dir, id } => Response::Release {
+                dir,
+                id,
+                release: Release::get(id).map(Box::new),
+            },
+            Request::Apply {
+                dir,
+                path,
+                release,
+                files,
+            } => {
+                let mut files = files
+                    .iter()
+                    .map(|f| File::new(f))
+                    .collect::<Result<Vec<_>>>()?;
+                SourceDir::new(&path)?.apply_discogs_to(&release, &mut files)?;
+                Response::Applied { dir }
+            }
+        })
+    }
+}
+
+/// Handles requests one at a time (to stay within rate limits), newest first,
+/// since the newest request is usually for the directory currently selected.
+fn worker(
+    rx: Receiver<Request>,
+    tx: Sender<Response>,
+) {
+    let mut queue = vec![];
+    while let Ok(req) = rx.recv() {
+        queue.push(req);
+        queue.extend(rx.try_iter());
+        while let Some(req) = queue.pop() {
+            if tx.send(req.handle()).is_err() {
+                return;
+            }
+            queue.extend(rx.try_iter());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Focus {
+    Dirs,
+    Candidates,
+}
+
+/// Computes the status of every directory sent, in order
+fn status_worker(
+    rx: Receiver<(usize, String)>,
+    tx: Sender<Response>,
+) {
+    for (dir, path) in rx {
+        let status = DirStatus::new(&path);
+        if tx.send(Response::Status { dir, status }).is_err() {
+            return;
+        }
+    }
+}
+
+/// Audio files of the selected directory, read once per selection
+struct LocalFiles {
+    dir: usize,
+    /// Error if the directory could not be read, or has no audio files
+    files: Result<Vec<File>, String>,
+}
 
 pub struct TaggerApp {
+    /// Selection in `visible` (not `items`); see `selected_dir`
     dir_state: ListState,
     items: Vec<DirEntry>,
+    /// Indices of `items` passing `filter`
+    visible: Vec<usize>,
+    filter: Option<Status>,
+    /// Status by directory index
+    statuses: HashMap<usize, DirStatus>,
+    status_requests: Sender<(usize, String)>,
+    local: Option<LocalFiles>,
+    /// Candidates by directory index
+    cache: HashMap<usize, Candidates>,
+    /// Errors from fetching candidates, by directory index. Not retried until
+    /// the next search.
+    errors: HashMap<usize, String>,
+    /// Bumped by every search (see `search`), so that candidates (or errors)
+    /// from earlier requests for the same directory are ignored
+    generations: HashMap<usize, usize>,
+    /// Keys of requests sent but not yet handled
+    pending: HashSet<RequestKey>,
+    requests: Sender<Request>,
+    responses: Receiver<Response>,
+    /// Query being edited; None when not in input mode
+    input: Option<String>,
+    /// Shown after a candidate is picked; None when not in alignment mode
+    alignment: Option<Alignment>,
+    /// Error from the last attempt to tag
+    error: Option<String>,
+    /// None when not in editing mode
+    editor: Option<TagEditor>,
+    focus: Focus,
+    help: bool,
 }
 
 impl TaggerApp {
     pub fn with_items(items: Vec<DirEntry>) -> Self {
+        let (requests, rx) = mpsc::channel();
+        let (tx, responses) = mpsc::channel();
+        let (status_requests, status_rx) = mpsc::channel();
+        let status_tx = tx.clone();
+        thread::spawn(move || worker(rx, tx));
+        thread::spawn(move || status_worker(status_rx, status_tx));
+        for (dir, item) in items.iter().enumerate() {
+            let _ = status_requests.send((dir, item.as_str().to_string()));
+        }
         TaggerApp {
             dir_state: {
                 // note: highlight only becomes visible when an item is selected
@@ -42,7 +916,24 @@ impl TaggerApp {
                 state.select(Some(0));
                 state
             },
+            visible: (0..items.len()).collect(),
             items,
+            filter: None,
+            statuses: HashMap::new(),
+            status_requests,
+            local: None,
+            cache: HashMap::new(),
+            errors: HashMap::new(),
+            generations: HashMap::new(),
+            pending: HashSet::new(),
+            requests,
+            responses,
+            input: None,
+            alignment: None,
+            error: None,
+            editor: None,
+            focus: Focus::Dirs,
+            help: false,
         }
     }
 
@@ -64,25 +955,78 @@ impl TaggerApp {
         mut terminal: Terminal<impl Backend>,
     ) -> io::Result<()> {
         loop {
+            self.receive();
+            self.load_files();
+            self.request();
             self.draw(&mut terminal)?;
 
-            if let Event::Key(key) = event::read()? {
-                if key.kind == KeyEventKind::Press {
-                    use KeyCode::*;
-                    match key.code {
-                        Char('q') | Esc => return Ok(()),
-
-                        Char('J') | PageDown => self.next(5),
-                        Char('K') | PageUp => self.previous(5),
-                        Char('j') | Down => self.next(1),
-                        Char('k') | Up => self.previous(1),
-
-                        // Char('h') | Left => self.items.unselect(),
-                        // Char('l') | Right | Enter => self.change_status(),
-                        // Char('g') => self.go_top(),
-                        // Char('G') => self.go_bottom(),
-                        _ => {}
+            // responses arrive independently of key presses, so don't block
+            if !event::poll(Duration::from_millis(100))? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            let keys = &CONFIG.tagger;
+            let typing =
+                self.input.is_some() || self.editor.as_ref().is_some_and(|e| e.input.is_some());
+            if self.help {
+                // any key closes help
+                self.help = false;
+            } else if !typing && keys.action(key.code, &[Action::Help]).is_some() {
+                self.help = true;
+            } else if self.input.is_some() {
+                self.edit_input(key.code);
+            } else if self.alignment.is_some() {
+                self.edit_alignment(key.code);
+            } else if self.editor.is_some() {
+                self.edit_tags(key.code);
+            } else if let Some(action) = keys.action(key.code, &Action::MAIN) {
+                match action {
+                    Action::Quit => return Ok(()),
+                    Action::Down => self.step(1, true),
+                    Action::Up => self.step(1, false),
+                    Action::PageDown => self.step(5, true),
+                    Action::PageUp => self.step(5, false),
+                    Action::Top => self.jump(false),
+                    Action::Bottom => self.jump(true),
+                    Action::NextCandidate => {
+                        if let Some(c) = self.candidates_mut() {
+                            c.next()
+                        }
+                    }
+                    Action::PreviousCandidate => {
+                        if let Some(c) = self.candidates_mut() {
+                            c.previous()
+                        }
+                    }
+                    Action::Pick => self.pick(),
+                    Action::Search => self.input = self.candidates().map(|c| c.query.to_string()),
+                    Action::Edit => {
+                        self.editor = self
+                            .selected_dir()
+                            .and_then(|i| SourceDir::new(self.items[i].as_str()).ok())
+                            .map(|d| TagEditor::new(d.files()))
+                    }
+                    Action::Focus => {
+                        self.focus = match self.focus {
+                            Focus::Dirs => Focus::Candidates,
+                            Focus::Candidates => Focus::Dirs,
+                        }
                     }
+                    Action::Filter => {
+                        self.filter = match self.filter {
+                            None => Some(Status::ALL[0]),
+                            Some(f) => Status::ALL.into_iter().skip_while(|s| *s != f).nth(1),
+                        };
+                        self.refilter();
+                    }
+                    Action::Help => self.help = true,
+                    _ => {}
                 }
             }
         }
@@ -98,41 +1042,506 @@ impl TaggerApp {
 
     // state management
 
-    /// Allows wrap-around
-    fn next(
+    /// Move within the focused list
+    fn step(
         &mut self,
         step: usize,
+        down: bool,
+    ) {
+        match (self.focus, down) {
+            (Focus::Dirs, true) => self.next(step),
+            (Focus::Dirs, false) => self.previous(step),
+            (Focus::Candidates, _) => {
+                if let Some(c) = self.candidates_mut() {
+                    for _ in 0..step {
+                        match down {
+                            true => c.next(),
+                            false => c.previous(),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Go to the top (or bottom) of the focused list
+    fn jump(
+        &mut self,
+        bottom: bool,
     ) {
-        // an item will always be selected in this app
-        let curr = self.dir_state.selected().unwrap();
+        match self.focus {
+            Focus::Dirs if !self.visible.is_empty() => self.dir_state.select(Some(match bottom {
+                true => self.visible.len() - 1,
+                false => 0,
+            })),
+            Focus::Dirs => {}
+            Focus::Candidates => {
+                if let Some(c) = self.candidates_mut() {
+                    c.current = match bottom {
+                        true => c.list.len().saturating_sub(1),
+                        false => 0,
+                    };
+                }
+            }
+        }
+    }
 
-        let new = curr
-            .ge(&(self.items.len() - step)) // last item, wrap-around
-            .then_some(0)
-            .or(Some(curr + step));
+    /// Read the files of the selected directory, if not already done
+    fn load_files(&mut self) {
+        let dir = self.selected_dir();
+        if self.local.as_ref().map(|l| l.dir) == dir {
+            return;
+        }
+        self.local = dir.map(|dir| LocalFiles {
+            dir,
+            files: read_audio(self.items[dir].as_str()).map_err(|e| format!("{e:#}")),
+        });
+    }
 
-        self.dir_state.select(new);
+    /// Files of the selected directory; empty if there are none, or on error
+    fn local_files(&self) -> &[File] {
+        match &self.local {
+            Some(LocalFiles {
+                files: Ok(files), ..
+            }) => files,
+            _ => &[],
+        }
     }
 
-    fn previous(
+    fn local_error(&self) -> Option<&str> {
+        match &self.local {
+            Some(LocalFiles { files: Err(e), .. }) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Index (in `items`) of the selected directory
+    fn selected_dir(&self) -> Option<usize> {
+        self.visible.get(self.dir_state.selected()?).copied()
+    }
+
+    fn ambiguous(
+        &self,
+        dir: usize,
+    ) -> Option<bool> {
+        self.cache.get(&dir)?.ambiguous()
+    }
+
+    /// Failing to fetch candidates (see `errors`) also counts as an error
+    fn has_status(
+        &self,
+        dir: usize,
+        status: Status,
+    ) -> bool {
+        self.statuses.get(&dir).is_some_and(|s| {
+            s.has(status, self.ambiguous(dir))
+                || (status == Status::Error && self.errors.contains_key(&dir))
+        })
+    }
+
+    /// Recompute `visible`. The selected directory stays selected if still
+    /// visible; otherwise, the selection stays in place.
+    fn refilter(&mut self) {
+        let selected = self.selected_dir();
+        let pos = self.dir_state.selected().unwrap_or(0);
+        self.visible = (0..self.items.len())
+            .filter(|d| self.filter.is_none_or(|s| self.has_status(*d, s)))
+            .collect();
+        let pos = selected
+            .and_then(|d| self.visible.iter().position(|v| *v == d))
+            .or((!self.visible.is_empty()).then(|| pos.min(self.visible.len() - 1)));
+        self.dir_state.select(pos);
+    }
+
+    /// Recompute the status of a directory after tagging
+    fn refresh_status(
         &mut self,
-        step: usize,
+        dir: usize,
     ) {
-        // let curr = self.dir_state.selected().unwrap();
-        // let new = curr
-        //     .eq(&0)
-        //     .then_some(self.items.len() - 1) // first item, wrap-around
-        //     .or(Some(curr - 1)); // curr-1 is an underflow, and i have no idea why
-
-        let new = {
-            let curr = self.dir_state.selected().unwrap();
-            match curr {
-                0 => self.items.len() - step,
-                _ => curr - step,
+        self.statuses.remove(&dir);
+        let _ = self
+            .status_requests
+            .send((dir, self.items[dir].as_str().to_string()));
+    }
+
+    fn candidates(&self) -> Option<&Candidates> { self.cache.get(&self.selected_dir()?) }
+
+    fn candidates_mut(&mut self) -> Option<&mut Candidates> {
+        self.cache.get_mut(&self.selected_dir()?)
+    }
+
+    fn send(
+        &mut self,
+        req: Request,
+    ) {
+        if self.pending.insert(req.key()) {
+            // if the worker is gone, nothing will load, but the UI stays usable
+            let _ = self.requests.send(req);
+        }
+    }
+
+    fn generation(
+        &self,
+        dir: usize,
+    ) -> usize {
+        self.generations.get(&dir).copied().unwrap_or_default()
+    }
+
+    /// Untagged directories whose candidates have not been fetched (nor
+    /// failed to), i.e. whose ambiguity is not known yet
+    fn unchecked(&self) -> Vec<usize> {
+        (0..self.items.len())
+            .filter(|d| {
+                self.has_status(*d, Status::Untagged)
+                    && !self.cache.contains_key(d)
+                    && !self.errors.contains_key(d)
+            })
+            .collect()
+    }
+
+    /// Request candidates for the selected directory and the next few (unless
+    /// already cached), and the release of the current candidate. Requests for
+    /// the selected directory are sent last, so that they are handled first.
+    ///
+    /// Ambiguity is only known once candidates are fetched, so while filtering
+    /// by it, candidates are requested for every unchecked directory as well
+    /// (not just visible ones); these are sent first, so they are handled last.
+    fn request(&mut self) {
+        if self.filter == Some(Status::Ambiguous) {
+            for dir in self.unchecked().into_iter().rev() {
+                self.request_candidates(dir);
+            }
+        }
+
+        let (Some(pos), Some(sel)) = (self.dir_state.selected(), self.selected_dir()) else {
+            return;
+        };
+        let len = self.visible.len();
+        let dirs = (0..=PREFETCH)
+            .rev()
+            .map(|d| self.visible[(pos + d) % len])
+            .collect_vec();
+        for dir in dirs {
+            if !self.cache.contains_key(&dir) && !self.errors.contains_key(&dir) {
+                self.request_candidates(dir);
+            }
+        }
+
+        let id = self
+            .candidates()
+            .and_then(|c| c.list.get(c.current))
+            .filter(|c| c.release.is_none() && !c.failed)
+            .map(|c| c.id);
+        if let Some(id) = id {
+            self.send(Request::Release { dir: sel, id });
+        }
+    }
+
+    /// With the initial query (see `Request::Candidates`)
+    fn request_candidates(
+        &mut self,
+        dir: usize,
+    ) {
+        let path = self.items[dir].as_str().to_string();
+        self.send(Request::Candidates {
+            dir,
+            generation: self.generation(dir),
+            path,
+            query: None,
+        });
+    }
+
+    fn receive(&mut self) {
+        let mut received = false;
+        while let Ok(resp) = self.responses.try_recv() {
+            received = true;
+            match resp {
+                Response::Candidates {
+                    generation,
+                    candidates: c,
+                } => {
+                    self.pending
+                        .remove(&RequestKey::Candidates(c.dir, generation));
+                    if generation == self.generation(c.dir) {
+                        self.cache.insert(c.dir, c);
+                    }
+                }
+                Response::Status { dir, status } => {
+                    self.statuses.insert(dir, status);
+                }
+                Response::Release { dir, id, release } => {
+                    self.pending.remove(&RequestKey::Release(dir, id));
+                    if let Some(cand) = self
+                        .cache
+                        .get_mut(&dir)
+                        .and_then(|c| c.list.iter_mut().find(|c| c.id == id))
+                    {
+                        cand.failed = release.is_none();
+                        cand.release = release.map(|r| *r);
+                    }
+                }
+                Response::Applied { dir } => {
+                    self.pending.remove(&RequestKey::Apply(dir));
+                    self.refresh_status(dir);
+                    // reread by `load_files`, so that the new tags are shown
+                    if self.local.as_ref().is_some_and(|l| l.dir == dir) {
+                        self.local = None;
+                    }
+                    // unless the user has moved on in the meantime
+                    if self.alignment.is_some() && self.selected_dir() == Some(dir) {
+                        self.error = None;
+                        self.alignment = None;
+                        self.next_untagged();
+                    }
+                }
+                Response::Error { key, error } => {
+                    self.pending.remove(&key);
+                    match key {
+                        RequestKey::Candidates(dir, generation) => {
+                            if generation == self.generation(dir) {
+                                self.errors.insert(dir, error);
+                            }
+                        }
+                        RequestKey::Release(dir, id) => {
+                            if let Some(cand) = self
+                                .cache
+                                .get_mut(&dir)
+                                .and_then(|c| c.list.iter_mut().find(|c| c.id == id))
+                            {
+                                cand.failed = true;
+                            }
+                        }
+                        RequestKey::Apply(_) => self.error = Some(error),
+                    }
+                }
+            }
+        }
+        if received && self.filter.is_some() {
+            self.refilter();
+        }
+    }
+
+    /// Replace the candidates of the selected directory. Requests already in
+    /// flight for it (e.g. prefetched with the initial query) are superseded.
+    fn search(
+        &mut self,
+        query: Query,
+    ) {
+        let Some(dir) = self.selected_dir() else {
+            return;
+        };
+        self.cache.remove(&dir);
+        self.errors.remove(&dir);
+        *self.generations.entry(dir).or_default() += 1;
+        let path = self.items[dir].as_str().to_string();
+        self.send(Request::Candidates {
+            dir,
+            generation: self.generation(dir),
+            path,
+            query: Some(query),
+        });
+    }
+
+    /// Input mode: `Enter` searches (unless the query is invalid), `Esc`
+    /// cancels
+    fn edit_input(
+        &mut self,
+        code: KeyCode,
+    ) {
+        let Some(input) = self.input.as_mut() else {
+            return;
+        };
+        match code {
+            KeyCode::Esc => self.input = None,
+            KeyCode::Enter => {
+                if let Ok(query) = input.parse() {
+                    self.input = None;
+                    self.search(query);
+                }
+            }
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Char(c) => input.push(c),
+            _ => {}
+        }
+    }
+
+    /// Mark the current candidate as the release to tag the directory with,
+    /// and align it with the local files
+    fn pick(&mut self) {
+        let Some(c) = self.candidates_mut() else {
+            return;
+        };
+        if c.list.get(c.current).is_none_or(|c| c.release.is_none()) {
+            return;
+        }
+        c.picked = Some(c.current);
+        let dir = c.dir;
+        self.alignment = SourceDir::new(self.items[dir].as_str())
+            .ok()
+            .map(|d| Alignment::new(&d));
+    }
+
+    fn picked_release(&self) -> Option<&Release> {
+        let c = self.candidates()?;
+        c.list.get(c.picked?)?.release.as_ref()
+    }
+
+    /// Alignment mode: select a file, move it or ignore it, then apply tags
+    /// (see `Action::ALIGNMENT`). `Back` returns to the candidates.
+    fn edit_alignment(
+        &mut self,
+        code: KeyCode,
+    ) {
+        let Some(alignment) = self.alignment.as_mut() else {
+            return;
+        };
+        match CONFIG.tagger.action(code, &Action::ALIGNMENT) {
+            Some(Action::Back) => self.alignment = None,
+            Some(Action::Down) => alignment.select(true),
+            Some(Action::Up) => alignment.select(false),
+            Some(Action::MoveDown) => alignment.shift(true),
+            Some(Action::MoveUp) => alignment.shift(false),
+            Some(Action::Ignore) => alignment.toggle_ignored(),
+            Some(Action::Apply) => self.apply(),
+            _ => {}
+        }
+    }
+
+    /// Tag the aligned files of the selected directory with the picked
+    /// release. This is done by the worker, as it can take a while (see
+    /// `SourceDir::apply_discogs_to`); the alignment is closed once done.
+    fn apply(&mut self) {
+        let (Some(c), Some(alignment), Some(rel)) = (
+            self.candidates(),
+            self.alignment.as_ref(),
+            self.picked_release(),
+        ) else {
+            return;
+        };
+        let req = Request::Apply {
+            dir: c.dir,
+            path: self.items[c.dir].as_str().to_string(),
+            release: Box::new(rel.clone()),
+            files: alignment.paths().map(|p| p.to_string()).collect(),
+        };
+        self.error = None;
+        self.send(req);
+    }
+
+    /// Editing mode (see `Action::EDITING`): `EditCell` edits the cell in
+    /// every marked row (or just the selected one), `Write` writes all staged
+    /// edits, `Back` leaves (twice, if there are unsaved edits). While a cell is
+    /// being edited, `Enter` stages the value and `Esc` cancels.
+    fn edit_tags(
+        &mut self,
+        code: KeyCode,
+    ) {
+        let Some(editor) = self.editor.as_mut() else {
+            return;
+        };
+        use KeyCode::*;
+
+        if let Some(input) = editor.input.as_mut() {
+            match code {
+                Esc => editor.input = None,
+                Enter => {
+                    let value = input.clone();
+                    match editor.stage(&value) {
+                        Ok(()) => {
+                            editor.input = None;
+                            editor.error = None;
+                        }
+                        Err(e) => editor.error = Some(format!("{e:#}")),
+                    }
+                }
+                Backspace => {
+                    input.pop();
+                }
+                Char(c) => input.push(c),
+                _ => {}
             }
+            return;
+        }
+
+        let keys = &CONFIG.tagger;
+        match keys.action(code, &Action::EDITING) {
+            Some(Action::Back) => {
+                let warning = format!(
+                    "unsaved edits; press {} again to discard",
+                    keys.describe(Action::Back)
+                );
+                match editor.staged.is_empty() || editor.error.as_ref() == Some(&warning) {
+                    true => self.editor = None,
+                    false => editor.error = Some(warning),
+                }
+            }
+            Some(Action::Down) => editor.select(true),
+            Some(Action::Up) => editor.select(false),
+            Some(Action::Right) => editor.select_col(true),
+            Some(Action::Left) => editor.select_col(false),
+            Some(Action::Mark) => editor.toggle_mark(),
+            Some(Action::MarkAll) => editor.toggle_all(),
+            Some(Action::EditCell) => {
+                if let Some(row) = editor.state.selected().filter(|r| *r < editor.files.len()) {
+                    editor.input = Some(editor.value(row, editor.field()));
+                }
+            }
+            Some(Action::Unstage) => editor.unstage(),
+            Some(Action::Write) => {
+                match editor.save() {
+                    Ok(()) => {
+                        editor.error = None;
+                        // reread by `load_files`, so that the new tags are shown
+                        self.local = None;
+                    }
+                    Err(e) => editor.error = Some(format!("{e:#}")),
+                }
+                if let Some(dir) = self.selected_dir() {
+                    self.refresh_status(dir);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Select the next visible directory known to be untagged, if any
+    fn next_untagged(&mut self) {
+        let Some(curr) = self.dir_state.selected() else {
+            return;
+        };
+        let len = self.visible.len();
+        if let Some(i) = (1..len)
+            .map(|d| (curr + d) % len)
+            .find(|i| self.has_status(self.visible[*i], Status::Untagged))
+        {
+            self.dir_state.select(Some(i));
+        }
+    }
+
+    /// Allows wrap-around (see `step_index`)
+    fn next(
+        &mut self,
+        step: usize,
+    ) {
+        // nothing is selected if no directory passes the filter
+        let Some(curr) = self.dir_state.selected() else {
+            return;
         };
+        self.dir_state
+            .select(step_index(curr, self.visible.len(), step, true));
+    }
 
-        self.dir_state.select(Some(new));
+    fn previous(
+        &mut self,
+        step: usize,
+    ) {
+        let Some(curr) = self.dir_state.selected() else {
+            return;
+        };
+        self.dir_state
+            .select(step_index(curr, self.visible.len(), step, false));
     }
 }
 
@@ -150,11 +1559,12 @@ impl Widget for &mut TaggerApp {
         let hsplit = Layout::vertical([
             Constraint::Length(6),
             Constraint::Length(1),
-            Constraint::Length(7),
+            Constraint::Length(8),
             Constraint::Length(1),
             Constraint::Min(0),
+            Constraint::Length(1),
         ]);
-        let [upper, _, middle, _, lower] = hsplit.areas(area);
+        let [upper, _, middle, _, lower, footer] = hsplit.areas(area);
 
         self.render_dirs(upper, buf);
         self.render_summary(middle, buf);
@@ -167,10 +1577,21 @@ impl Widget for &mut TaggerApp {
 
         let vsplit = Layout::horizontal(Constraint::from_percentages([49, 2, 49]));
         let [left, _, right] = vsplit.areas(lower);
-        self.render_tags(left, buf);
-        self.render_discogs(right, buf);
+        if self.local_error().is_some() {
+            self.render_error(lower, buf);
+        } else if self.alignment.is_some() {
+            self.render_alignment(lower, buf);
+        } else if self.editor.is_some() {
+            self.render_editor(lower, buf);
+        } else {
+            self.render_tags(left, buf);
+            self.render_discogs(right, buf);
+        }
 
-        // TODO: footer with keybindings
+        self.render_footer(footer, buf);
+        if self.help {
+            self.render_help(area, buf);
+        }
     }
 }
 
@@ -180,37 +1601,80 @@ impl TaggerApp {
         area: Rect,
         buf: &mut Buffer,
     ) {
-        // TODO: basename
-        let items: Vec<ListItem> = self.items.iter().map(|f| f.as_list_item()).collect();
-        StatefulWidget::render(
-            List::new(items).highlight_symbol("> "),
-            area,
-            buf,
-            &mut self.dir_state,
-        );
+        let items: Vec<ListItem> = self
+            .visible
+            .iter()
+            .map(|d| {
+                let markers = match self.statuses.get(d) {
+                    Some(s) => s.markers(self.ambiguous(*d), self.errors.contains_key(d)),
+                    None => Span::from("  "),
+                };
+                let name = self.items[*d].file_name().to_string_lossy().to_string();
+                ListItem::new(Line::from(vec![markers, Span::from(" "), Span::from(name)]))
+            })
+            .collect();
+        let list = List::new(items).highlight_symbol("> ");
+        let list = match self.filter {
+            Some(f) => {
+                let mut title = format!(
+                    "{} ({}/{}",
+                    f.as_str(),
+                    self.visible.len(),
+                    self.items.len()
+                );
+                if f == Status::Ambiguous {
+                    title += &format!(", {} unchecked", self.unchecked().len());
+                }
+                list.block(Block::default().title(title + ")"))
+            }
+            None => list,
+        };
+        let list = match self.focus {
+            Focus::Dirs => list.highlight_style(Style::new().bold()),
+            Focus::Candidates => list,
+        };
+        StatefulWidget::render(list, area, buf, &mut self.dir_state);
     }
 
+    /// Of the first file
     pub fn render_summary(
         &mut self,
         area: Rect,
         buf: &mut Buffer,
-    ) -> Result<()> {
-        let f = self.get_files().find(|f| f.file_type().is_file()).unwrap();
-        let f = File::new(f.as_str())?;
-
-        let summary = f.to_string();
-        let list = List::new(summary.split('\n'))
+    ) {
+        let summary = match self.local_files().first() {
+            Some(f) => f.to_string(),
+            None => String::new(),
+        };
+        let list = List::new(summary.lines())
             .block(Block::default().borders(Borders::ALL).title("summary"));
         Widget::render(list, area, buf);
+    }
 
-        Ok(())
+    /// Shown instead of tags and candidates if the directory has no audio
+    pub fn render_error(
+        &mut self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let Some(e) = self.local_error() else {
+            return;
+        };
+        Widget::render(
+            Paragraph::new(e.to_string())
+                .red()
+                .wrap(Wrap { trim: false })
+                .block(Block::default().borders(Borders::ALL).title("error")),
+            area,
+            buf,
+        );
     }
 
     pub fn get_files(&self) -> impl Iterator<Item = DirEntry> + '_ {
-        self.items
-            .get(self.dir_state.selected().unwrap())
-            .unwrap()
-            .walk()
+        self.selected_dir()
+            .map(|d| self.items[d].walk())
+            .into_iter()
+            .flatten()
     }
 
     pub fn render_files(
@@ -235,16 +1699,18 @@ impl TaggerApp {
         area: Rect,
         buf: &mut Buffer,
     ) {
-        let files = self
-            .get_files()
-            .filter(|f| f.file_type().is_file())
-            .map(|f| f.as_str().to_string())
-            .sorted()
-            .map(|f| File::new(&f))
-            .filter_map(|f| f.ok());
-        let items = files
-            .into_iter()
-            .map(|f| f.tags.title().unwrap().to_string());
+        // files without a title are shown by name
+        let items: Vec<String> = self
+            .local_files()
+            .iter()
+            .map(|f| match f.tags.title() {
+                Some(title) => title.to_string(),
+                None => Path::new(&f.path)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+            })
+            .collect();
         Widget::render(
             List::new(items).block(Block::default().title("tags")),
             area,
@@ -256,34 +1722,324 @@ impl TaggerApp {
         &mut self,
         area: Rect,
         buf: &mut Buffer,
-    ) -> Result<()> {
-        let f = self.get_files().find(|f| f.file_type().is_file()).unwrap();
-        let f = File::new(f.as_str())?;
-
-        let results = Release::search(
-            &f.get(TagField::Artist).unwrap(),
-            &f.get(TagField::Album).unwrap(),
-        )
-        .results;
+    ) {
+        let title = match (&self.input, self.candidates()) {
+            (Some(input), _) => format!("/{input}_"),
+            (None, Some(c)) => format!("/{}", c.query),
+            (None, None) => String::new(),
+        };
+        let block = Block::default().borders(Borders::LEFT).title(title);
+        let Some(c) = self.candidates().filter(|c| !c.list.is_empty()) else {
+            let dir = self.selected_dir();
+            let text = match dir.and_then(|d| self.errors.get(&d)) {
+                _ if dir.is_some_and(|d| {
+                    self.pending
+                        .contains(&RequestKey::Candidates(d, self.generation(d)))
+                }) =>
+                {
+                    Text::from("loading...")
+                }
+                Some(e) => Text::from(e.as_str()).red(),
+                None => Text::from("not found"),
+            };
+            Widget::render(
+                Paragraph::new(text).wrap(Wrap { trim: false }).block(block),
+                area,
+                buf,
+            );
+            return;
+        };
 
-        // TODO: cache results into some hashmap
-        // TODO: iterate through results (h/l); requires extra state in TaggerApp
+        let vsplit = Layout::vertical([
+            Constraint::Length(c.list.len() as u16 + 1),
+            Constraint::Min(0),
+        ]);
+        let [upper, lower] = vsplit.areas(block.inner(area));
+        Widget::render(block, area, buf);
 
-        let block = Block::default().borders(Borders::LEFT);
+        let items = c.list.iter().enumerate().map(|(i, cand)| {
+            let score = match c.score(i) {
+                Some(s) => format!("{s:>3}%"),
+                None => "   ?".to_string(),
+            };
+            let line = format!(
+                "{} {score} {}",
+                match c.picked == Some(i) {
+                    true => "*",
+                    false => " ",
+                },
+                cand.summary,
+            );
+            match i == c.current {
+                true => ListItem::new(line).reversed(),
+                false => ListItem::new(line),
+            }
+        });
+        Widget::render(
+            List::new(items).block(Block::default().title({
+                let title = Line::from(format!("candidate {}/{}", c.current + 1, c.list.len()));
+                match self.focus {
+                    Focus::Candidates => title.bold(),
+                    Focus::Dirs => title,
+                }
+            })),
+            upper,
+            buf,
+        );
 
-        let list = match results.first() {
-            Some(res) => {
-                let rel = res.as_rel();
+        let cand = &c.list[c.current];
+        let list = match &cand.release {
+            Some(rel) => {
                 let tracks = rel.tracklist();
                 let items = tracks.iter().map(|t| t.to_string());
-                // TODO: search url?
-                List::new(items).block(block.title(rel.uri.clone()))
+                List::new(items).block(Block::default().title(rel.uri.clone()))
+            }
+            None if cand.failed => {
+                List::default().block(Block::default().title("could not fetch release"))
             }
-            None => List::default().block(block.title("not found")),
+            None => List::default().block(Block::default().title("loading release...")),
         };
-        Widget::render(list, area, buf);
+        Widget::render(list, lower, buf);
+    }
 
-        Ok(())
+    /// Local files (left) against the Discogs tracklist (right). Duration
+    /// deltas over 5 seconds and dissimilar titles are highlighted.
+    pub fn render_alignment(
+        &mut self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let applying = self
+            .selected_dir()
+            .is_some_and(|d| self.pending.contains(&RequestKey::Apply(d)));
+        // not `picked_release`, which would borrow all of `self`
+        let rel = self
+            .selected_dir()
+            .and_then(|d| self.cache.get(&d))
+            .and_then(|c| c.list.get(c.picked?)?.release.as_ref());
+        let (Some(rel), Some(alignment)) = (rel, self.alignment.as_mut()) else {
+            return;
+        };
+        let discogs = rel.tracklist();
+        let mut discogs = discogs.iter().zip(rel.durations());
+
+        let fmt_dur = |d: Option<u32>| match d {
+            Some(d) if d > 0 => format!("{}:{:02}", d / 60, d % 60),
+            _ => String::new(),
+        };
+
+        let mut rows = vec![];
+        for t in &alignment.tracks {
+            if t.ignored {
+                rows.push(
+                    Row::new([t.title.clone(), fmt_dur(t.duration), "ignored".to_string()])
+                        .dim()
+                        .crossed_out(),
+                );
+                continue;
+            }
+            let Some((track, dur)) = discogs.next() else {
+                rows.push(Row::new([t.title.clone(), fmt_dur(t.duration)]).red());
+                continue;
+            };
+            let delta = match t.duration {
+                Some(d) if dur > 0 => {
+                    let delta = d as i64 - dur as i64;
+                    let cell = Cell::from(format!("{delta:+}"));
+                    match delta.abs() > 5 {
+                        true => cell.red(),
+                        false => cell.green(),
+                    }
+                }
+                _ => Cell::from(""),
+            };
+            let title = Cell::from(track.title.clone());
+            let title = match jaro_winkler(&normalize(&t.title), &normalize(&track.title))
+                >= FUZZY_THRESHOLD
+            {
+                true => title,
+                false => title.yellow(),
+            };
+            rows.push(Row::new([
+                Cell::from(t.title.clone()),
+                Cell::from(fmt_dur(t.duration)),
+                delta,
+                Cell::from(fmt_dur(Some(dur))),
+                title,
+            ]));
+        }
+        for (track, dur) in discogs {
+            rows.push(
+                Row::new([
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                    fmt_dur(Some(dur)),
+                    track.title.clone(),
+                ])
+                .red(),
+            );
+        }
+
+        let title = match &self.error {
+            Some(e) => Line::from(e.as_str()).red(),
+            None if applying => Line::from(format!("{rel}: applying...")),
+            None => Line::from(format!("{} ({})", rel, rel.uri)),
+        };
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Percentage(40),
+                Constraint::Length(6),
+                Constraint::Length(6),
+                Constraint::Length(6),
+                Constraint::Percentage(40),
+            ],
+        )
+        .header(Row::new(["file", "", "delta", "", "discogs"]).bold())
+        .block(Block::default().borders(Borders::TOP).title(title))
+        .highlight_symbol("> ");
+        StatefulWidget::render(table, area, buf, &mut alignment.state);
+    }
+
+    /// Staged values are highlighted; the cell being edited shows the input.
+    pub fn render_editor(
+        &mut self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let Some(editor) = self.editor.as_mut() else {
+            return;
+        };
+        let selected = editor.state.selected();
+        let rows: Vec<Row> = (0..editor.files.len())
+            .map(|row| {
+                let marked = match editor.marked.contains(&row) {
+                    true => "*",
+                    false => " ",
+                };
+                let cells = TagField::ALL.iter().enumerate().map(|(col, field)| {
+                    let editing = editor.input.is_some()
+                        && col == editor.col
+                        && match editor.marked.is_empty() {
+                            true => selected == Some(row),
+                            false => editor.marked.contains(&row),
+                        };
+                    let cell = match (editing, &editor.input) {
+                        (true, Some(input)) => Cell::from(format!("{input}_")),
+                        _ => Cell::from(editor.value(row, *field)),
+                    };
+                    let cell = match editor.staged.contains_key(&(row, *field)) {
+                        true => cell.yellow(),
+                        false => cell,
+                    };
+                    match selected == Some(row) && col == editor.col {
+                        true => cell.reversed(),
+                        false => cell,
+                    }
+                });
+                Row::new(std::iter::once(Cell::from(marked)).chain(cells))
+            })
+            .collect();
+
+        let title = match &editor.error {
+            Some(e) => Line::from(e.as_str()).red(),
+            None => Line::from(format!("{} staged", editor.staged.len())),
+        };
+        let widths = [
+            Constraint::Length(1),
+            Constraint::Length(5),
+            Constraint::Percentage(30),
+            Constraint::Percentage(20),
+            Constraint::Percentage(20),
+            Constraint::Length(4),
+            Constraint::Percentage(10),
+            Constraint::Length(10),
+        ];
+        let header = std::iter::once("").chain(TagField::ALL.iter().map(|f| f.as_str()));
+        let table = Table::new(rows, widths)
+            .header(Row::new(header).bold())
+            .block(Block::default().borders(Borders::TOP).title(title));
+        StatefulWidget::render(table, area, buf, &mut editor.state);
+    }
+
+    /// Keys for the current mode
+    pub fn render_footer(
+        &mut self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let keys = &CONFIG.tagger;
+        let text = if self.input.is_some() {
+            "<enter> search | <esc> cancel".to_string()
+        } else if self.alignment.is_some() {
+            keys.summary(&Action::ALIGNMENT, " | ")
+        } else if self.editor.as_ref().is_some_and(|e| e.input.is_some()) {
+            "<enter> stage | <esc> cancel".to_string()
+        } else if self.editor.is_some() {
+            keys.summary(&Action::EDITING, " | ")
+        } else {
+            keys.summary(
+                &[
+                    Action::Down,
+                    Action::Up,
+                    Action::NextCandidate,
+                    Action::Pick,
+                    Action::Search,
+                    Action::Edit,
+                    Action::Focus,
+                    Action::Filter,
+                    Action::Help,
+                    Action::Quit,
+                ],
+                " | ",
+            )
+        };
+        Widget::render(Paragraph::new(text).dim(), area, buf);
+    }
+
+    /// All keys, over the rest of the UI
+    pub fn render_help(
+        &mut self,
+        area: Rect,
+        buf: &mut Buffer,
+    ) {
+        let keys = &CONFIG.tagger;
+        let mut rows: Vec<Row> = Action::MAIN
+            .iter()
+            .map(|a| Row::new([keys.describe(*a), a.as_str().to_string()]))
+            .collect();
+        for (mode, text) in [
+            ("alignment", keys.summary(&Action::ALIGNMENT, ", ")),
+            ("editing", keys.summary(&Action::EDITING, ", ")),
+            ("input", "<enter> confirm, <esc> cancel".to_string()),
+            (
+                "markers",
+                "* tagged, - untagged, ? ambiguous, ! error, ~ needs transcoding".to_string(),
+            ),
+        ] {
+            rows.push(Row::new([mode.to_string(), text]).dim());
+        }
+
+        let [_, area, _] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(rows.len() as u16 + 2),
+            Constraint::Min(0),
+        ])
+        .areas(area);
+        let [_, area, _] =
+            Layout::horizontal(Constraint::from_percentages([10, 80, 10])).areas(area);
+
+        Widget::render(Clear, area, buf);
+        Widget::render(
+            Table::new(rows, [Constraint::Length(16), Constraint::Min(0)]).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("help (any key to close)"),
+            ),
+            area,
+            buf,
+        );
     }
 }
 
@@ -291,3 +2047,170 @@ pub fn main() {
     let dir = SourceDir::new(&SOURCE).unwrap();
     TaggerApp::with_items(dir.dirs()).main().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::OnceCell;
+
+    use id3::frame::ExtendedText;
+    use id3::TagLike;
+    use ratatui::widgets::TableState;
+
+    use crate::tagger::match_score;
+    use crate::tagger::step_index;
+    use crate::tagger::Alignment;
+    use crate::tagger::DirStatus;
+    use crate::tagger::LocalTrack;
+    use crate::tagger::Query;
+    use crate::tagger::Status;
+    use crate::transcode::File;
+    use crate::transcode::FileType;
+
+    #[test]
+    fn test_match_score() {
+        assert_eq!(match_score(&[Some(100), Some(200)], &[101, 210]), 50);
+        assert_eq!(match_score(&[Some(100), None], &[100, 200]), 50);
+        assert_eq!(match_score(&[Some(100)], &[100, 200]), 50); // missing track
+        assert_eq!(match_score(&[Some(0)], &[0]), 0); // no discogs duration
+        assert_eq!(match_score(&[], &[]), 0);
+    }
+
+    #[test]
+    fn test_alignment() {
+        let track = |path: &str| LocalTrack {
+            path: path.to_string(),
+            title: String::new(),
+            duration: None,
+            ignored: false,
+        };
+        let mut alignment = Alignment {
+            tracks: vec![track("a"), track("b"), track("c")],
+            state: TableState::default().with_selected(Some(0)),
+        };
+        alignment.shift(false); // already first
+        alignment.shift(true);
+        assert_eq!(alignment.paths().collect::<Vec<_>>(), ["b", "a", "c"]);
+        assert_eq!(alignment.state.selected(), Some(1));
+
+        alignment.toggle_ignored();
+        assert_eq!(alignment.paths().collect::<Vec<_>>(), ["b", "c"]);
+
+        alignment.select(false);
+        alignment.select(false); // wrap-around
+        assert_eq!(alignment.state.selected(), Some(2));
+    }
+
+    #[test]
+    fn test_dir_status() {
+        let untagged = DirStatus {
+            transcode: true,
+            ..Default::default()
+        };
+        assert!(untagged.has(Status::Untagged, None));
+        assert!(untagged.has(Status::Transcode, None));
+        assert!(!untagged.has(Status::Ambiguous, None));
+        assert!(untagged.has(Status::Ambiguous, Some(true)));
+        assert_eq!(untagged.markers(Some(true), false).content, "?~");
+        assert_eq!(untagged.markers(Some(true), true).content, "!~");
+
+        let tagged = DirStatus {
+            tagged: true,
+            ..Default::default()
+        };
+        assert!(!tagged.has(Status::Untagged, None));
+        assert!(!tagged.has(Status::Ambiguous, Some(true)));
+
+        let error = DirStatus::new("/nonexistent");
+        assert!(error.has(Status::Error, None));
+        assert!(!error.has(Status::Untagged, None));
+    }
+
+    #[test]
+    fn test_step_index() {
+        // fewer items than the step
+        assert_eq!(step_index(1, 3, 5, true), Some(2));
+        assert_eq!(step_index(2, 3, 5, true), Some(0));
+        assert_eq!(step_index(1, 3, 5, false), Some(0));
+        assert_eq!(step_index(0, 3, 5, false), Some(2));
+
+        assert_eq!(step_index(3, 10, 5, true), Some(8));
+        assert_eq!(step_index(8, 10, 5, false), Some(3));
+        assert_eq!(step_index(0, 1, 1, true), Some(0));
+        assert_eq!(step_index(0, 0, 1, true), None);
+    }
+
+    #[test]
+    fn test_query_from_catalog() {
+        let mut file = File {
+            path: String::new(),
+            file_type: FileType::FLAC,
+            tags: id3::Tag::new(),
+            properties: OnceCell::new(),
+        };
+        assert_eq!(Query::from_catalog(&file), None);
+        for (description, value) in [("CATALOGNUMBER", "CL 1355"), ("BARCODE", "074646493524")] {
+            file.tags.add_frame(ExtendedText {
+                description: description.to_string(),
+                value: value.to_string(),
+            });
+        }
+        // a barcode is preferred
+        assert_eq!(
+            Query::from_catalog(&file),
+            Some(Query::Barcode("074646493524".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let parse = |s: &str| s.parse::<Query>().unwrap();
+        assert_eq!(parse("r:377464"), Query::Release(377464));
+        assert_eq!(parse("m: 6440"), Query::Master(6440));
+        assert!("r:foo".parse::<Query>().is_err());
+        // bare numbers are titles, not IDs
+        assert_eq!(
+            parse("1984"),
+            Query::Search {
+                artist: String::new(),
+                album: "1984".to_string()
+            }
+        );
+        assert_eq!(
+            parse("https://www.discogs.com/release/377464-Metallica-Ride-The-Lightning"),
+            Query::Release(377464)
+        );
+        assert_eq!(
+            parse("https://www.discogs.com/master/6440-Metallica-Ride-The-Lightning"),
+            Query::Master(6440)
+        );
+        assert!("https://www.discogs.com/artist/18839"
+            .parse::<Query>()
+            .is_err());
+        assert_eq!(
+            parse("barcode: 075596021227"),
+            Query::Barcode("075596021227".to_string())
+        );
+        assert_eq!(parse("catno:MFN 27"), Query::Catno("MFN 27".to_string()));
+        assert_eq!(
+            parse("Metallica - Ride the Lightning"),
+            Query::Search {
+                artist: "Metallica".to_string(),
+                album: "Ride the Lightning".to_string()
+            }
+        );
+
+        // round trip
+        for s in [
+            "Metallica - Ride the Lightning",
+            "madvillainy",
+            "1999",
+            "m83",
+            "r:1",
+            "m:2",
+            "barcode:1",
+            "catno:x",
+        ] {
+            assert_eq!(parse(s).to_string(), s);
+        }
+    }
+}
@@ -1,26 +1,42 @@
 //! Transcoding and preservation of metadata across formats
 
+use std::cell::OnceCell;
+use std::collections::HashSet;
 use std::fmt::Display;
 use std::fs;
 use std::iter::zip;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::Command;
 use std::process::Stdio;
 
 use anyhow::Context;
 use anyhow::Result;
+use id3::frame::ExtendedText;
 use id3::TagLike;
+use itertools::Itertools;
 use lofty::AudioFile;
 use lofty::ParseOptions;
+use lofty::TagExt;
 use ratatui::widgets::ListItem;
+use serde::Deserialize;
+use serde::Serialize;
 use walkdir::DirEntry;
 use walkdir::WalkDir;
 
+use crate::config::CONFIG;
+use crate::cue::CueSheet;
+use crate::cue::GapMode;
+use crate::cue::Split;
 use crate::io::Sort;
 use crate::io::Walk;
+use crate::loudness;
+use crate::loudness::Loudness;
 use crate::release::Release;
+use crate::verify::is_audio;
 
 /// Mainly for transcoding. For metadata, id3 is always used.
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum FileType {
     // Lossy
     MP3,
@@ -34,8 +50,12 @@ pub enum FileType {
     Unknown,
 }
 
+impl FileType {
+    pub fn is_lossless(&self) -> bool { matches!(self, FileType::WAV | FileType::FLAC) }
+}
+
 /// Used in `Track` and `File`
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TagField {
     Artist,
     Album,
@@ -43,17 +63,446 @@ pub enum TagField {
     Title,
     TrackNumber,
     Genre,
+    /// Stored as a TXXX frame (or the equivalent Vorbis comment), so that
+    /// tagged albums can be matched with the collection without searching
+    DiscogsReleaseId,
 }
 
+impl TagField {
+    pub const ALL: [TagField; 7] = [
+        TagField::TrackNumber,
+        TagField::Title,
+        TagField::Artist,
+        TagField::Album,
+        TagField::Year,
+        TagField::Genre,
+        TagField::DiscogsReleaseId,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            TagField::Artist => "artist",
+            TagField::Album => "album",
+            TagField::Year => "year",
+            TagField::Title => "title",
+            TagField::TrackNumber => "track",
+            TagField::Genre => "genre",
+            TagField::DiscogsReleaseId => "discogs id",
+        }
+    }
+
+    /// Numeric fields must parse; `File::set` would otherwise silently write 0.
+    /// An empty value removes the field.
+    pub fn validate(
+        &self,
+        value: &str,
+    ) -> Result<()> {
+        if value.is_empty() {
+            return Ok(());
+        }
+        match self {
+            TagField::Year => {
+                value
+                    .parse::<i32>()
+                    .with_context(|| format!("invalid year: {value}"))?;
+            }
+            TagField::TrackNumber => {
+                value
+                    .parse::<u32>()
+                    .with_context(|| format!("invalid track number: {value}"))?;
+            }
+            TagField::DiscogsReleaseId => {
+                value
+                    .parse::<usize>()
+                    .with_context(|| format!("invalid release ID: {value}"))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Description of the TXXX frame holding `TagField::DiscogsReleaseId`
+pub const DISCOGS_RELEASE_ID: &str = "DISCOGS_RELEASE_ID";
+
+/// Vorbis comment for each field, when reading and writing FLAC tags
+// 2nd value should be [&str], probably, to cover multiple possible field names, e.g.
+// 'DATE'/'YEAR'
+/// Identifiers carried over from cue sheets (see `CueSheet::track_tags`).
+/// Stored as TXXX frames in `File::tags`, so that they survive transcoding, and
+/// can be used to search Discogs.
+pub const CATALOG_FIELDS: [&str; 2] = ["BARCODE", "CATALOGNUMBER"];
+
+const VORBIS_FIELDS: [(TagField, &str); 7] = [
+    (TagField::Title, "TITLE"),
+    (TagField::TrackNumber, "TRACKNUMBER"),
+    (TagField::Artist, "ARTIST"),
+    (TagField::Album, "ALBUM"),
+    (TagField::Year, "DATE"),
+    (TagField::Genre, "GENRE"),
+    (TagField::DiscogsReleaseId, DISCOGS_RELEASE_ID),
+];
+
 #[derive(Debug)]
 // not sure how this should be implemented
 pub enum TranscodeResult {
     Success,
+    /// Written to the output root without re-encoding
+    Copied,
     NotNeeded,
     Unrecognized,
     Failure,
 }
 
+/// Encoder settings. All targets are lossy; there is no point in transcoding
+/// into a lossless format.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// `lame -V <n>`, where 0 is the highest quality
+    Mp3Vbr(u8),
+    /// `lame -b <kbps> --cbr`
+    Mp3Cbr(u32),
+    /// `opusenc --bitrate <kbps>`
+    Opus(u32),
+}
+
+impl Target {
+    pub fn extension(&self) -> &str {
+        match self {
+            Target::Mp3Vbr(_) | Target::Mp3Cbr(_) => "mp3",
+            Target::Opus(_) => "opus",
+        }
+    }
+
+    /// Input and output paths are left to the caller.
+    fn encoder(&self) -> Command {
+        let mut cmd = match self {
+            Target::Mp3Vbr(_) | Target::Mp3Cbr(_) => Command::new("lame"),
+            Target::Opus(_) => Command::new("opusenc"),
+        };
+        match self {
+            Target::Mp3Vbr(q) => cmd.args(["--silent", "-V", &q.to_string()]),
+            Target::Mp3Cbr(b) => cmd.args(["--silent", "--cbr", "-b", &b.to_string()]),
+            Target::Opus(b) => cmd.args(["--quiet", "--bitrate", &b.to_string()]),
+        };
+        cmd
+    }
+}
+
+/// Parse the shorthand used on the command line, e.g. `mp3-v0`, `mp3-320`,
+/// `opus-160`.
+impl std::str::FromStr for Target {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (codec, setting) = s
+            .to_lowercase()
+            .split_once('-')
+            .map(|(c, s)| (c.to_string(), s.to_string()))
+            .context("target should be of the form <codec>-<setting>")?;
+        Ok(match (codec.as_str(), setting.strip_prefix('v')) {
+            ("mp3", Some(q)) => Target::Mp3Vbr(q.parse()?),
+            ("mp3", None) => Target::Mp3Cbr(setting.parse()?),
+            ("opus", None) => Target::Opus(setting.parse()?),
+            _ => anyhow::bail!("unknown target: {s}"),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TranscodeAction {
+    /// Leave the file alone
+    Skip,
+    /// Keep the audio as is (no re-encoding)
+    Copy,
+    Transcode(Target),
+}
+
+/// What happens to the original file after a successful transcode. In all
+/// cases, the original is only touched once the new file has been verified.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveMode {
+    /// Remove the original
+    Delete,
+    /// Leave the original next to the new file
+    Keep,
+    /// Move the original into an archive tree, which mirrors the structure of
+    /// the source directory
+    Move(PathBuf),
+    /// Like `Move`, but the original is also left in place. Both paths must be
+    /// on the same filesystem.
+    Hardlink(PathBuf),
+}
+
+/// Parse the shorthand used on the command line: `delete`, `keep`,
+/// `move:<dir>`, `hardlink:<dir>`.
+impl std::str::FromStr for ArchiveMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.split_once(':') {
+            None if s == "delete" => ArchiveMode::Delete,
+            None if s == "keep" => ArchiveMode::Keep,
+            Some(("move", dir)) => ArchiveMode::Move(PathBuf::from(dir)),
+            Some(("hardlink", dir)) => ArchiveMode::Hardlink(PathBuf::from(dir)),
+            _ => anyhow::bail!("unknown archive mode: {s}"),
+        })
+    }
+}
+
+impl ArchiveMode {
+    /// `root` is the directory that the archive tree mirrors, i.e. `path` is
+    /// archived to `<archive>/<path relative to root>`.
+    pub fn archive(
+        &self,
+        path: &Path,
+        root: &Path,
+    ) -> Result<()> {
+        let dest = |archive: &Path| -> Result<PathBuf> {
+            let dest = archive.join(path.strip_prefix(root)?);
+            fs::create_dir_all(dest.parent().context("no parent")?)?;
+            anyhow::ensure!(!dest.exists(), "already archived: {}", dest.display());
+            Ok(dest)
+        };
+
+        match self {
+            ArchiveMode::Delete => fs::remove_file(path)?,
+            ArchiveMode::Keep => {}
+            ArchiveMode::Hardlink(archive) => fs::hard_link(path, dest(archive)?)?,
+            ArchiveMode::Move(archive) => {
+                let dest = dest(archive)?;
+                // rename fails across filesystems
+                if fs::rename(path, &dest).is_err() {
+                    fs::copy(path, &dest)?;
+                    fs::remove_file(path)?;
+                }
+            }
+        };
+        Ok(())
+    }
+}
+
+/// A single rule of a `TranscodePolicy`. Bitrates are in kbps, and both bounds
+/// are inclusive; a missing bound matches everything.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TranscodeRule {
+    pub input: FileType,
+    #[serde(default)]
+    pub min_bitrate: Option<u32>,
+    #[serde(default)]
+    pub max_bitrate: Option<u32>,
+    pub action: TranscodeAction,
+}
+
+impl TranscodeRule {
+    fn has_bounds(&self) -> bool { self.min_bitrate.is_some() || self.max_bitrate.is_some() }
+
+    fn matches(
+        &self,
+        file_type: FileType,
+        bitrate: Option<u32>,
+    ) -> bool {
+        if self.input != file_type {
+            return false;
+        }
+        // if the bitrate cannot be determined, only unbounded rules apply
+        match bitrate {
+            Some(b) => {
+                self.min_bitrate.unwrap_or(0) <= b && b <= self.max_bitrate.unwrap_or(u32::MAX)
+            }
+            None => !self.has_bounds(),
+        }
+    }
+}
+
+/// Decides what to do with each file, based on its format and bitrate. Rules
+/// are checked in order, and the first match wins; files matching no rule are
+/// skipped.
+///
+/// The default policy transcodes lossless files to MP3 V0, and leaves all MP3s
+/// untouched.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TranscodePolicy {
+    pub rules: Vec<TranscodeRule>,
+    /// Re-encoding a lossy file only ever loses information; if false (the
+    /// default), any rule that would do so is downgraded to `Skip`.
+    pub allow_lossy_to_lossy: bool,
+    /// Defaults to `Delete`, or to `Keep` if `output_root` is set; see
+    /// `archive_mode`
+    pub archive: Option<ArchiveMode>,
+    /// If set, new files are written here (mirroring the structure of the
+    /// source directory) instead of next to the originals. The source
+    /// directory is then left untouched, unless `archive` moves the originals
+    /// elsewhere; `Delete` is not allowed.
+    pub output_root: Option<PathBuf>,
+}
+
+impl Default for TranscodePolicy {
+    fn default() -> Self {
+        let rule = |input| TranscodeRule {
+            input,
+            min_bitrate: None,
+            max_bitrate: None,
+            action: TranscodeAction::Transcode(Target::Mp3Vbr(0)),
+        };
+        Self {
+            rules: vec![rule(FileType::FLAC), rule(FileType::WAV)],
+            allow_lossy_to_lossy: false,
+            archive: None,
+            output_root: None,
+        }
+    }
+}
+
+impl TranscodePolicy {
+    /// Replace the target of every `Transcode` rule.
+    pub fn with_target(
+        mut self,
+        target: Target,
+    ) -> Self {
+        for rule in self.rules.iter_mut() {
+            if let TranscodeAction::Transcode(_) = rule.action {
+                rule.action = TranscodeAction::Transcode(target);
+            }
+        }
+        self
+    }
+
+    pub fn with_lossy_to_lossy(
+        mut self,
+        allow: bool,
+    ) -> Self {
+        self.allow_lossy_to_lossy = allow;
+        self
+    }
+
+    pub fn with_archive(
+        mut self,
+        archive: ArchiveMode,
+    ) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    pub fn with_output_root(
+        mut self,
+        root: PathBuf,
+    ) -> Self {
+        self.output_root = Some(root);
+        self
+    }
+
+    /// What happens to originals, once `archive` and `output_root` are taken
+    /// into account
+    pub fn archive_mode(&self) -> ArchiveMode {
+        match (&self.archive, &self.output_root) {
+            (Some(archive), _) => archive.clone(),
+            (None, Some(_)) => ArchiveMode::Keep,
+            (None, None) => ArchiveMode::Delete,
+        }
+    }
+
+    /// Writing to an output root is meant to leave the source directory
+    /// intact, so originals may not be deleted.
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            !(self.output_root.is_some() && self.archive == Some(ArchiveMode::Delete)),
+            "originals cannot be deleted when writing to an output root"
+        );
+        Ok(())
+    }
+
+    /// `bitrate` is only evaluated if a rule for the file type has bitrate
+    /// bounds, as it requires reading the file.
+    pub fn decide(
+        &self,
+        file_type: FileType,
+        bitrate: impl FnOnce() -> Option<u32>,
+    ) -> TranscodeAction {
+        let bitrate = match self
+            .rules
+            .iter()
+            .any(|r| r.input == file_type && r.has_bounds())
+        {
+            true => bitrate(),
+            false => None,
+        };
+
+        let action = self
+            .rules
+            .iter()
+            .find(|r| r.matches(file_type, bitrate))
+            .map(|r| r.action)
+            .unwrap_or(TranscodeAction::Skip);
+
+        match action {
+            TranscodeAction::Transcode(_)
+                if !file_type.is_lossless() && !self.allow_lossy_to_lossy =>
+            {
+                TranscodeAction::Skip
+            }
+            a => a,
+        }
+    }
+}
+
+/// Read from the audio stream itself (as opposed to tags), so these are always
+/// accurate, regardless of how the file was tagged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioProperties {
+    /// Milliseconds
+    pub duration: u64,
+    /// e.g. FLAC, MPEG, Opus
+    pub codec: String,
+    pub sample_rate: Option<u32>,
+    /// Only available for lossless formats
+    pub bit_depth: Option<u8>,
+    pub channels: Option<u8>,
+    /// Audio bitrate in kbps (i.e. excluding tags and other metadata)
+    pub bitrate: Option<u32>,
+}
+
+impl AudioProperties {
+    pub fn read(path: &str) -> Result<Self> {
+        let file = lofty::read_from_path(path)?;
+        let props = file.properties();
+        Ok(Self {
+            duration: props.duration().as_millis() as u64,
+            codec: format!("{:?}", file.file_type()).to_uppercase(),
+            sample_rate: props.sample_rate(),
+            bit_depth: props.bit_depth(),
+            channels: props.channels(),
+            bitrate: props.audio_bitrate(),
+        })
+    }
+}
+
+/// `FLAC, 44100 Hz, 16 bit, 2 ch, 912 kbps, 3:45`
+impl Display for AudioProperties {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        write!(f, "{}", self.codec)?;
+        if let Some(sr) = self.sample_rate {
+            write!(f, ", {sr} Hz")?;
+        }
+        if let Some(bd) = self.bit_depth {
+            write!(f, ", {bd} bit")?;
+        }
+        if let Some(ch) = self.channels {
+            write!(f, ", {ch} ch")?;
+        }
+        if let Some(br) = self.bitrate {
+            write!(f, ", {br} kbps")?;
+        }
+        let secs = self.duration / 1000;
+        write!(f, ", {}:{:02}", secs / 60, secs % 60)
+    }
+}
+
 /// Wrapper over `id3::Tag`. It is important to note that metadata can be read
 /// and stored completely separately from the audio file. Implements some
 /// transcoding methods for convenience.
@@ -65,8 +514,13 @@ pub struct File {
 
     /// To avoid the need for an adapter over different audio formats and tag
     /// containers (and since I always transcode into MP3), we default to
-    /// `id3`. Under the hood, other crates like `lofty` are used.
+    /// `id3`. Under the hood, other crates like `lofty` are used. FLAC tags
+    /// are read into (and written from) this; see `write_tags`.
     pub tags: id3::Tag,
+
+    /// Read on first access (see `File::properties`), since this requires
+    /// parsing the entire stream.
+    pub properties: OnceCell<Option<AudioProperties>>,
 }
 
 // #[derive(Debug)]
@@ -76,13 +530,25 @@ pub struct File {
 // }
 
 impl File {
+    /// Files without an audio extension (e.g. images, cue sheets) are not
+    /// parsed at all, and have `FileType::Unknown`.
     pub fn new(path: &str) -> Result<Self> {
+        let mut f = Self {
+            path: path.to_string(),
+            file_type: FileType::Unknown,
+            tags: id3::Tag::new(),
+            properties: OnceCell::new(),
+        };
+        if !is_audio(Path::new(path)) {
+            return Ok(f);
+        }
+
         let ft = infer::get_from_path(path)
             .context("read file")?
             .context("infer filetype")?
             .extension(); // note: this disregards the actual filetype
 
-        let file_type = match ft {
+        f.file_type = match ft {
             "mp3" => FileType::MP3,
             "flac" => FileType::FLAC,
             "wav" => FileType::WAV,
@@ -90,24 +556,27 @@ impl File {
         };
 
         // init with empty tags, so we can use File.get for convenience
-        let f = Self {
-            path: path.to_string(),
-            file_type,
-            tags: {
-                match id3::Tag::read_from_path(path) {
-                    Ok(tags) => tags,
-                    _ => id3::Tag::new(),
-                }
-            },
-        };
+        if let Ok(tags) = id3::Tag::read_from_path(path) {
+            f.tags = tags;
+        }
+        if let FileType::FLAC = f.file_type {
+            // a flac without vorbis comments is simply untagged
+            let _ = f.read_flac_tags();
+        }
 
         Ok(f)
     }
 
-    fn copy_flac_tags(
-        &mut self,
-        new_path: &str,
-    ) -> Result<()> {
+    /// None if the stream could not be parsed; this does not necessarily mean
+    /// that the file is unplayable.
+    pub fn properties(&self) -> Option<&AudioProperties> {
+        self.properties
+            .get_or_init(|| AudioProperties::read(&self.path).ok())
+            .as_ref()
+    }
+
+    /// Populate `self.tags` from the file's vorbis comments. Done by `new`.
+    fn read_flac_tags(&mut self) -> Result<()> {
         // TODO: opus metadata
 
         // metaflac: vorbis comments are stored internally as hashmap, but API doesn't
@@ -120,33 +589,80 @@ impl File {
 
         // lofty is probably the cleanest way to do it
         let mut buf = std::fs::File::open(&self.path)?;
-        let flacfile = lofty::flac::FlacFile::read_from(&mut buf, ParseOptions::default())?;
+        let flacfile =
+            lofty::flac::FlacFile::read_from(&mut buf, ParseOptions::new().read_properties(false))?;
         let comments = flacfile.vorbis_comments().context("no vorbis comments")?;
 
-        println!("{:?}", comments);
-
         // TODO: can this be turned into a match statement for exhaustiveness?
-        for (tag, com) in [
-            // 2nd value should be [&str], probably, to cover multiple possible field names, e.g.
-            // 'DATE'/'YEAR'
-            (TagField::Title, "TITLE"),
-            (TagField::TrackNumber, "TRACKNUMBER"),
-            (TagField::Artist, "ARTIST"),
-            (TagField::Album, "ALBUM"),
-            (TagField::Year, "DATE"),
-            (TagField::Genre, "GENRE"),
-        ] {
+        for (tag, com) in VORBIS_FIELDS {
             if let Some(val) = comments.get(com) {
                 // TODO: genre should be titlecase
                 self.set(tag, val);
             }
         }
+        for com in CATALOG_FIELDS {
+            if let Some(val) = comments.get(com) {
+                self.tags.add_frame(ExtendedText {
+                    description: com.to_string(),
+                    value: val.to_string(),
+                });
+            }
+        }
 
-        self.tags.write_to_path(new_path, id3::Version::Id3v24)?;
+        Ok(())
+    }
 
+    /// Write `self.tags` back to the file: as id3 for MP3, as vorbis comments
+    /// (see `VORBIS_FIELDS`, `CATALOG_FIELDS` and `loudness::FIELDS`) for FLAC.
+    /// Other vorbis comments are preserved. Tags are never written to other
+    /// formats.
+    pub fn write_tags(&self) -> Result<()> {
+        match self.file_type {
+            FileType::MP3 => self.tags.write_to_path(&self.path, id3::Version::Id3v24)?,
+            FileType::FLAC => {
+                let mut buf = std::fs::File::open(&self.path)?;
+                let flacfile = lofty::flac::FlacFile::read_from(
+                    &mut buf,
+                    ParseOptions::new().read_properties(false),
+                )?;
+                let mut comments = flacfile.vorbis_comments().cloned().unwrap_or_default();
+                for (tag, com) in VORBIS_FIELDS {
+                    match self.get(tag) {
+                        Some(val) => comments.insert(com.to_string(), val),
+                        // only remove comments that were read into the tag
+                        // (and then cleared), not those that failed to parse
+                        // (e.g. a full date)
+                        None if comments.get(com).is_some_and(|v| tag.validate(v).is_ok()) => {
+                            comments.remove(com).for_each(drop)
+                        }
+                        None => {}
+                    }
+                }
+                for t in self.tags.extended_texts().filter(|t| {
+                    let desc = t.description.as_str();
+                    loudness::FIELDS.contains(&desc) || CATALOG_FIELDS.contains(&desc)
+                }) {
+                    comments.insert(t.description.clone(), t.value.clone());
+                }
+                comments.save_to_path(&self.path)?;
+            }
+            ft => anyhow::bail!("cannot write tags to {ft:?}: {}", self.path),
+        }
         Ok(())
     }
 
+    /// Value of the TXXX frame with this description, e.g. one of
+    /// `CATALOG_FIELDS`
+    pub fn extended(
+        &self,
+        description: &str,
+    ) -> Option<&str> {
+        self.tags
+            .extended_texts()
+            .find(|t| t.description == description)
+            .map(|t| t.value.as_str())
+    }
+
     pub fn get(
         &self,
         field: TagField,
@@ -159,22 +675,51 @@ impl File {
             TagField::Year => self.tags.year().map(|f| f.to_string()),
             TagField::TrackNumber => self.tags.track().map(|f| f.to_string()),
             TagField::Genre => self.tags.genre_parsed().map(|f| f.to_string()),
+            TagField::DiscogsReleaseId => self
+                .tags
+                .extended_texts()
+                .find(|t| t.description == DISCOGS_RELEASE_ID)
+                .map(|t| t.value.clone()),
             // _ => None,
         }
         // .map(|f| f.to_string())
     }
 
-    fn set(
+    pub fn set(
         &mut self,
         field: TagField,
         value: &str,
     ) {
+        if value.is_empty() {
+            match field {
+                TagField::Title => self.tags.remove_title(),
+                TagField::Artist => self.tags.remove_artist(),
+                TagField::Album => self.tags.remove_album(),
+                TagField::Genre => self.tags.remove_genre(),
+                TagField::Year => {
+                    self.tags.remove_year();
+                    self.tags.remove_date_recorded();
+                }
+                TagField::TrackNumber => self.tags.remove_track(),
+                TagField::DiscogsReleaseId => self
+                    .tags
+                    .remove_extended_text(Some(DISCOGS_RELEASE_ID), None),
+            }
+            return;
+        }
+
         // Tag.set_X cannot fail, apparently
         match field {
             TagField::Title => self.tags.set_title(value),
             TagField::Artist => self.tags.set_artist(value),
             TagField::Album => self.tags.set_album(value),
             TagField::Genre => self.tags.set_genre(value),
+            TagField::DiscogsReleaseId => {
+                self.tags.add_frame(ExtendedText {
+                    description: DISCOGS_RELEASE_ID.to_string(),
+                    value: value.to_string(),
+                });
+            }
 
             // why is year i32? no idea
             TagField::Year => match value.parse::<i32>() {
@@ -206,78 +751,293 @@ impl File {
         // let kbps = size / mp3dur * 8;
         // println!("{} {} {}", mp3dur, size, kbps);
 
-        let mut buf = std::fs::File::open(&self.path)?;
-        let newfile = lofty::mpeg::MpegFile::read_from(&mut buf, ParseOptions::default())?;
-        Ok(newfile.properties().audio_bitrate())
+        self.properties()
+            .and_then(|p| p.bitrate)
+            .context("could not determine bitrate")
     }
 
-    /// The target encoding is always MP3 V0 (for now). Shell commands are used
-    /// because I haven't found a crate that does lossy transcoding at a low
-    /// level.
+    /// What should be done with this file, according to `policy`.
+    pub fn transcode_action(
+        &self,
+        policy: &TranscodePolicy,
+    ) -> TranscodeAction {
+        policy.decide(self.file_type, || self.bitrate().ok())
+    }
+
+    /// Shell commands are used because I haven't found a crate that does lossy
+    /// transcoding at a low level.
     ///
     /// - Extract tags as id3 (if present)
-    /// - Transcode to mp3
-    /// - Write id3 tags to new mp3 file
-    fn transcode(&mut self) -> Result<TranscodeResult> {
-        if let FileType::MP3 = self.file_type
-        // // requires nightly
-        //     && self.bitrate()? < 320
-        {
-            if self.bitrate()? < 320 {
-                return Ok(TranscodeResult::NotNeeded);
-            }
-        }
-
-        // "lame --silent -V 0 --disptime 1";
+    /// - Transcode to the target dictated by `policy`
+    /// - Write tags to the new file
+    /// - Verify the new file, move it into place, then archive the original
+    ///   (see `ArchiveMode`); `root` is the source directory being transcoded
+    fn transcode(
+        &mut self,
+        policy: &TranscodePolicy,
+        root: &str,
+    ) -> Result<TranscodeResult> {
+        let target = match self.file_type {
+            FileType::Unknown => return Ok(TranscodeResult::Unrecognized),
+            _ => match self.transcode_action(policy) {
+                TranscodeAction::Transcode(target) => target,
+                TranscodeAction::Copy => return self.copy(policy, root),
+                TranscodeAction::Skip => return Ok(TranscodeResult::NotNeeded),
+            },
+        };
 
         // https://doc.rust-lang.org/std/process/index.html#handling-io
 
         // "flac in.flac --decode --stdout --totally-silent |
         // lame --silent -V 0 - out.mp3"
 
-        // println!("{:#?}", f.tags);
+        // everything is written to a temporary file first, which is only renamed once
+        // it has been verified
+        let Some(dest) = self.output_path(target.extension(), policy, root)? else {
+            return Ok(TranscodeResult::NotNeeded);
+        };
+        let outfile = partial_path(&dest)?;
 
-        let outfile = format!("{}.mp3", self.path);
+        let mut encoder = target.encoder();
+        if let Target::Opus(_) = target {
+            // opusenc does not read id3, so tags are passed as args
+            encoder.args(self.opus_tag_args());
+        }
 
-        match self.file_type {
-            FileType::MP3 => {
-                // lame preserves metadata automatically
-                Command::new("lame")
-                    .arg(&self.path)
-                    .args("--silent -V 0".split_whitespace())
+        let status = match (self.file_type, target) {
+            // lame reads mp3 and wav directly, opusenc reads wav and flac directly
+            (FileType::MP3 | FileType::WAV, Target::Mp3Vbr(_) | Target::Mp3Cbr(_))
+            | (FileType::WAV | FileType::FLAC, Target::Opus(_)) => {
+                encoder.arg(&self.path).arg(&outfile).spawn()?.wait()?
+            }
+            _ => {
+                let mut decoder = self.decoder().stdout(Stdio::piped()).spawn()?;
+                let encoded = encoder
+                    .arg("-")
+                    // if you decide to collect the output bytes and write the buffer yourself,
+                    // the new file will have incorrect duration
                     .arg(&outfile)
+                    .stdin(Stdio::from(
+                        decoder.stdout.take().context("no decoder stdout")?,
+                    ))
                     .spawn()?
                     .wait()?;
+                // a decoder that fails midway just ends the stream, which the encoder
+                // happily accepts
+                match decoder.wait()? {
+                    decoded if !decoded.success() => decoded,
+                    _ => encoded,
+                }
             }
+        };
+
+        if !status.success() {
+            let _ = fs::remove_file(&outfile);
+            return Ok(TranscodeResult::Failure);
+        }
+
+        if let Target::Mp3Vbr(_) | Target::Mp3Cbr(_) = target {
+            self.tags.write_to_path(&outfile, id3::Version::Id3v24)?;
+        }
+
+        if let Err(e) = self.verify_output(&outfile) {
+            println!("verification failed, keeping original: {e:#}");
+            fs::remove_file(&outfile)?;
+            return Ok(TranscodeResult::Failure);
+        }
+
+        // the original is only archived once the new file is in place, unless the new
+        // file replaces it (see `output_path`)
+        let original = Path::new(&self.path);
+        let archive = policy.archive_mode();
+        match (dest == original, &archive) {
+            (true, ArchiveMode::Delete) => fs::rename(&outfile, &dest)?,
+            (true, _) => {
+                archive.archive(original, Path::new(root))?;
+                fs::rename(&outfile, &dest)?;
+            }
+            (false, _) => {
+                fs::rename(&outfile, &dest)?;
+                archive.archive(original, Path::new(root))?;
+            }
+        }
+        self.path = dest.to_string_lossy().to_string();
+
+        Ok(TranscodeResult::Success)
+    }
+
+    /// Copying in place is the same as leaving the file alone, so this only
+    /// does anything if an output root is set. The original is never archived.
+    fn copy(
+        &mut self,
+        policy: &TranscodePolicy,
+        root: &str,
+    ) -> Result<TranscodeResult> {
+        if policy.output_root.is_none() {
+            return Ok(TranscodeResult::NotNeeded);
+        }
+        let ext = Path::new(&self.path)
+            .extension()
+            .context("no extension")?
+            .to_string_lossy()
+            .to_string();
+        let Some(dest) = self.output_path(&ext, policy, root)? else {
+            return Ok(TranscodeResult::NotNeeded);
+        };
+        let outfile = partial_path(&dest)?;
+        fs::copy(&self.path, &outfile)?;
+        fs::rename(&outfile, &dest)?;
+        Ok(TranscodeResult::Copied)
+    }
 
+    /// The original path, with the extension replaced by `ext`, relocated
+    /// under `policy.output_root` (if set). Existing files are never
+    /// overwritten; `None` is returned instead, as the file has presumably been
+    /// transcoded in a previous run (e.g. with `ArchiveMode::Keep`). The only
+    /// exception is the original itself, if it will have been deleted or moved
+    /// away by the time the new file is renamed.
+    fn output_path(
+        &self,
+        ext: &str,
+        policy: &TranscodePolicy,
+        root: &str,
+    ) -> Result<Option<PathBuf>> {
+        let path = Path::new(&self.path);
+        let base = match &policy.output_root {
+            Some(out) => out.join(path.strip_prefix(root)?),
+            None => path.to_path_buf(),
+        };
+        fs::create_dir_all(base.parent().context("no parent")?)?;
+
+        let out = base.with_extension(ext);
+        let replaceable = out == path
+            && matches!(
+                policy.archive_mode(),
+                ArchiveMode::Delete | ArchiveMode::Move(_)
+            );
+        Ok((!out.exists() || replaceable).then_some(out))
+    }
+
+    /// Decode `outfile` in its entirety (see `check_decode`), and check that
+    /// its duration matches the original's to within a second. Encoders add a
+    /// little padding, so an exact match is not expected.
+    fn verify_output(
+        &self,
+        outfile: &Path,
+    ) -> Result<()> {
+        check_decode(outfile)?;
+        let (old, new) = (duration_ms(Path::new(&self.path))?, duration_ms(outfile)?);
+        anyhow::ensure!(
+            old.abs_diff(new) <= 1000,
+            "duration mismatch: {old} ms -> {new} ms"
+        );
+        Ok(())
+    }
+
+    /// Decode to wav on stdout
+    fn decoder(&self) -> Command {
+        match self.file_type {
             FileType::FLAC => {
-                let flac = Command::new("flac")
+                let mut cmd = Command::new("flac");
+                cmd.args("--decode --stdout --totally-silent".split_whitespace())
+                    .arg(&self.path);
+                cmd
+            }
+            // lame can also decode mp3
+            FileType::MP3 => {
+                let mut cmd = Command::new("lame");
+                cmd.args("--silent --decode".split_whitespace())
                     .arg(&self.path)
-                    .args("--decode --stdout --totally-silent".split_whitespace())
-                    .stdout(Stdio::piped())
-                    .spawn()?;
-                let mut lame = Command::new("lame")
-                    .args("--silent -V 0 -".split_whitespace())
-                    // if you decide to collect the output bytes and write the buffer yourself,
-                    // the new file will have incorrect duration
-                    .arg(&outfile)
-                    .stdin(Stdio::from(flac.stdout.context("no flac stdout")?))
-                    .spawn()?;
-                lame.wait()?;
-                self.copy_flac_tags(&outfile)?;
+                    .arg("-");
+                cmd
             }
-            FileType::Unknown => return Ok(TranscodeResult::Unrecognized),
+            // e.g. opus, which lame cannot decode
+            _ => {
+                let mut cmd = Command::new("ffmpeg");
+                cmd.args(["-hide_banner", "-loglevel", "error", "-i"])
+                    .arg(&self.path)
+                    .args(["-f", "wav", "-"]);
+                cmd
+            }
+        }
+    }
 
-            // "ffmpeg -y -i";
-            // "lame --silent {BITRATE_ARG} --disptime 1";
-            _ => unimplemented!(),
-        };
+    fn opus_tag_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = [
+            (TagField::Title, "--title"),
+            (TagField::Artist, "--artist"),
+            (TagField::Album, "--album"),
+            (TagField::Year, "--date"),
+            (TagField::Genre, "--genre"),
+            (TagField::TrackNumber, "--tracknumber"),
+        ]
+        .into_iter()
+        .filter_map(|(tag, arg)| self.get(tag).map(|val| [arg.to_string(), val]))
+        .flatten()
+        .collect();
+        if let Some(id) = self.get(TagField::DiscogsReleaseId) {
+            args.extend([
+                "--comment".to_string(),
+                format!("{DISCOGS_RELEASE_ID}={id}"),
+            ]);
+        }
+        for com in CATALOG_FIELDS {
+            if let Some(val) = self.extended(com) {
+                args.extend(["--comment".to_string(), format!("{com}={val}")]);
+            }
+        }
+        args
+    }
+}
 
-        fs::remove_file(&self.path)?;
-        self.path = outfile;
+/// Decode `path` in its entirety (with ffmpeg); any decoder output is an error.
+fn check_decode(path: &Path) -> Result<()> {
+    let out = Command::new("ffmpeg")
+        .args(["-v", "error", "-i"])
+        .arg(path)
+        .args(["-f", "null", "-"])
+        .stdin(Stdio::null())
+        .output()?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    anyhow::ensure!(
+        out.status.success() && stderr.trim().is_empty(),
+        "could not decode {}: {}",
+        path.display(),
+        stderr.trim()
+    );
+    Ok(())
+}
 
-        Ok(TranscodeResult::Success)
+/// Milliseconds, read from the audio stream
+fn duration_ms(path: &Path) -> Result<u128> {
+    Ok(lofty::read_from_path(path)?
+        .properties()
+        .duration()
+        .as_millis())
+}
+
+/// Every track must decode, and together they must be as long as the image
+/// (to within a second), minus any audio before the first track.
+fn verify_split(split: &Split) -> Result<()> {
+    let mut total = 0;
+    for track in &split.tracks {
+        check_decode(track)?;
+        total += duration_ms(track)?;
     }
+    let expected = duration_ms(&split.image)?.saturating_sub(split.skipped as u128);
+    anyhow::ensure!(
+        expected.abs_diff(total) <= 1000,
+        "duration mismatch: {} is {expected} ms, tracks are {total} ms",
+        split.image.display()
+    );
+    Ok(())
+}
+
+/// Hidden file in the same directory as `dest` (so that it can be renamed
+/// atomically). The extension is preserved, as some tools rely on it.
+fn partial_path(dest: &Path) -> Result<PathBuf> {
+    let name = dest.file_name().context("no file name")?.to_string_lossy();
+    Ok(dest.with_file_name(format!(".part.{name}")))
 }
 
 impl Display for File {
@@ -290,6 +1050,10 @@ impl Display for File {
         writeln!(f, "artist: {}", self.tags.artist().unwrap_or("none"))?;
         writeln!(f, "album: {}", self.tags.album().unwrap_or("none"))?;
         writeln!(f, "year: {}", self.tags.year().unwrap_or(0))?;
+        match self.properties() {
+            Some(props) => writeln!(f, "audio: {props}")?,
+            None => writeln!(f, "audio: unknown")?,
+        }
         Ok(())
     }
 }
@@ -297,6 +1061,9 @@ impl Display for File {
 pub struct SourceDir {
     pub path: String,
     pub dir: DirEntry,
+    /// Write ReplayGain tags after transcoding/tagging. Defaults to the
+    /// `replaygain` config value.
+    pub replaygain: bool,
 }
 
 impl Walk for DirEntry {
@@ -326,56 +1093,224 @@ impl SourceDir {
         Ok(Self {
             path: path.to_string(),
             dir,
+            replaygain: CONFIG.replaygain,
         })
     }
 
+    pub fn with_replaygain(
+        mut self,
+        replaygain: bool,
+    ) -> Self {
+        self.replaygain = replaygain;
+        self
+    }
+
     pub fn dirs(&self) -> Vec<DirEntry> { self.dir.sort(false) }
 
+    /// Audio files only, sorted by path. Subdirectories (e.g. `CD1`, `CD2`)
+    /// are included, so that multi-disc releases are treated as one.
     pub fn files(&self) -> Vec<File> {
-        self.dir
-            .sort(true)
-            .iter()
-            .map(|f| f.as_str())
-            // .walk()
-            // .map(|f| f.as_str())
-            // .sorted()
-            .map(File::new)
-            .filter_map(|p| p.ok())
+        WalkDir::new(&self.path)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| File::new(e.as_str()).ok())
+            .filter(|f| f.file_type != FileType::Unknown)
+            .collect()
+    }
+
+    /// Cue sheets (and their paths) that reference at least one image
+    fn cue_sheets(&self) -> Vec<(PathBuf, CueSheet)> {
+        WalkDir::new(&self.path)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|f| f.ok())
+            .filter(|f| {
+                f.path()
+                    .extension()
+                    .is_some_and(|e| e.eq_ignore_ascii_case("cue"))
+            })
+            .filter_map(|e| match CueSheet::load(e.path()) {
+                Ok(cue) if cue.is_image() => Some((e.path().to_path_buf(), cue)),
+                Ok(_) => None,
+                Err(err) => {
+                    println!("err: {e:?}: {err:#}");
+                    None
+                }
+            })
             .collect()
     }
 
-    // TODO: log errors
-    pub fn transcode_all(&self) -> Result<()> {
+    /// Split every single-file image (with an accompanying cue sheet) into
+    /// tracks. An image is only archived (according to `policy`) once its
+    /// tracks have been verified (see `verify_split`); the sheet, once all of
+    /// its images have been. Images split by a previous run are skipped.
+    pub fn split_cues(
+        &self,
+        policy: &TranscodePolicy,
+    ) -> Result<()> {
+        let root = Path::new(&self.path);
+        let archive = policy.archive_mode();
+        for (path, cue) in self.cue_sheets() {
+            println!("splitting: {path:?}");
+            let splits = match cue.split(&path, GapMode::Append) {
+                Ok(splits) => splits,
+                Err(err) => {
+                    println!("err: {path:?}: {err:#}");
+                    continue;
+                }
+            };
+            // images split in a previous run are skipped; if that is all of them,
+            // the sheet has already been archived (or is kept)
+            let mut verified = !splits.is_empty();
+            for split in splits {
+                match verify_split(&split) {
+                    Ok(()) => archive.archive(&split.image, root)?,
+                    Err(err) => {
+                        println!("verification failed, keeping image: {err:#}");
+                        // so that the image is split again next time
+                        for track in &split.tracks {
+                            fs::remove_file(track)?;
+                        }
+                        verified = false;
+                    }
+                }
+            }
+            if verified {
+                archive.archive(&path, root)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Errors are reported per file; they do not stop the batch.
+    pub fn transcode_all(
+        &self,
+        policy: &TranscodePolicy,
+    ) -> Result<()> {
+        policy.validate()?;
+        self.split_cues(policy)?;
+
+        // images left in place (e.g. with `ArchiveMode::Keep`) have already been
+        // split, and should not be transcoded as well
+        let images: HashSet<PathBuf> = self
+            .cue_sheets()
+            .iter()
+            .flat_map(|(path, cue)| cue.images(path))
+            .collect();
+
         for e in WalkDir::new(&self.path)
             .sort_by(|a, b| a.file_name().cmp(b.file_name()))
             .into_iter()
             .filter_map(|f| f.ok())
-            .filter(|f| f.path().is_file())
+            .filter(|f| f.path().is_file() && !images.contains(f.path()))
         {
             let f = File::new(e.as_str());
             match f {
                 Err(_) => println!("err: {e:?}"),
                 Ok(mut f) => {
                     println!("converting: {e:?}");
-                    f.transcode().unwrap();
+                    if let Err(err) = f.transcode(policy, &self.path) {
+                        println!("err: {e:?}: {err:#}");
+                    }
+                }
+            }
+        }
+
+        if self.replaygain {
+            match &policy.output_root {
+                // the source directory is never written to in this mode
+                Some(out) if out.is_dir() => {
+                    SourceDir::new(&out.to_string_lossy())?.tag_loudness()?
                 }
+                Some(_) => {}
+                None => self.tag_loudness()?,
             }
         }
 
         Ok(())
     }
 
-    /// Some quirks:
-    ///
-    /// - `TagLike::duration()` may return `Some(0)`, for some reason; not sure
-    ///   if `None` can be returned
-    /// - durations are returned in milliseconds, so we convert to seconds
+    /// Scan every MP3 and FLAC file under this directory, and write track and
+    /// album gain. Each direct child of this directory is treated as one album,
+    /// so discs in subdirectories (e.g. `CD1`, `CD2`) share the same album gain.
+    /// Files directly under this directory are treated as one album.
+    pub fn tag_loudness(&self) -> Result<()> {
+        let albums = self
+            .loudness_files()
+            .into_iter()
+            .into_group_map_by(|f| self.album_dir(&f.path));
+
+        for (dir, files) in albums {
+            Self::tag_album_loudness(&dir, files)?;
+        }
+
+        Ok(())
+    }
+
+    fn loudness_files(&self) -> Vec<File> {
+        self.files()
+            .into_iter()
+            .filter(|f| matches!(f.file_type, FileType::MP3 | FileType::FLAC))
+            .collect()
+    }
+
+    /// The direct child of this directory that contains `path`, or this
+    /// directory itself if `path` is not nested
+    fn album_dir(
+        &self,
+        path: &str,
+    ) -> PathBuf {
+        let root = Path::new(&self.path);
+        match Path::new(path)
+            .strip_prefix(root)
+            .map(|p| p.components().collect_vec())
+        {
+            Ok(c) if c.len() > 1 => root.join(c[0]),
+            _ => root.to_path_buf(),
+        }
+    }
+
+    /// Files that cannot be scanned are reported, and the album is skipped.
+    fn tag_album_loudness(
+        dir: &Path,
+        mut files: Vec<File>,
+    ) -> Result<()> {
+        let tracks = match files
+            .iter()
+            .map(|f| Loudness::scan(&f.path))
+            .collect::<Result<Vec<_>>>()
+        {
+            Ok(tracks) => tracks,
+            Err(e) => {
+                println!("err: could not scan {dir:?}: {e:#}");
+                return Ok(());
+            }
+        };
+        let album = Loudness::album(&tracks);
+        for (file, track) in files.iter_mut().zip(&tracks) {
+            loudness::write_tags(&mut file.tags, track, album.as_ref());
+            file.write_tags()?;
+        }
+        Ok(())
+    }
+
+    /// Durations in seconds (rounded), as used by Discogs. Durations are
+    /// computed from the audio stream; the TLEN tag (`TagLike::duration()`) is
+    /// absent on most files, and frequently `Some(0)` otherwise.
     pub fn durations(&self) -> Vec<Option<u32>> {
+        self.durations_ms()
+            .iter()
+            .map(|d| d.map(|d| ((d + 500) / 1000) as u32)) // Option.map in Iterator.map is wild
+            .collect()
+    }
+
+    pub fn durations_ms(&self) -> Vec<Option<u64>> {
         self.files()
             .iter()
-            // warning: duration may be inaccurate if not properly encoded
-            .map(|t| t.tags.duration()) //.unwrap_or(0))
-            .map(|d| d.map(|d| d / 1000)) // Option.map in Iterator.map is wild
+            .map(|f| f.properties().map(|p| p.duration))
             .collect()
     }
 }
@@ -411,7 +1346,17 @@ impl SourceDir {
         &mut self,
         rel: &Release,
     ) -> Result<()> {
-        for (discogs_track, file) in rel.tracklist().iter().zip(&mut self.files()) {
+        self.apply_discogs_to(rel, &mut self.files())
+    }
+
+    /// Like `apply_discogs`, but `files` (not necessarily all files in the
+    /// directory) are paired with the Discogs tracklist in the given order.
+    pub fn apply_discogs_to(
+        &self,
+        rel: &Release,
+        files: &mut [File],
+    ) -> Result<()> {
+        for (discogs_track, file) in rel.tracklist().iter().zip(files) {
             // println!("{}\n{}", discogs_track, file);
 
             // println!("{}\n{:?}", discogs_track.title, file.get(TagField::Title));
@@ -427,10 +1372,21 @@ impl SourceDir {
             file.set(TagField::Artist, &rel.artists_sort);
             file.set(TagField::Album, &rel.title);
             file.set(TagField::Year, &rel.year.to_string());
-            file.tags.write_to_path(&file.path, id3::Version::Id3v24)?;
+            file.set(TagField::DiscogsReleaseId, &rel.id.to_string());
+            file.write_tags()?;
 
             //
         }
+
+        if self.replaygain {
+            // one release, regardless of subdirectories
+            Self::tag_album_loudness(Path::new(&self.path), self.loudness_files())?;
+        }
+
+        if CONFIG.collection.add_after_tagging {
+            CONFIG.collection.add_if_missing(rel.id)?;
+        }
+
         Ok(())
     }
 }
@@ -439,16 +1395,33 @@ impl SourceDir {
 mod tests {
     //{{{
 
+    use std::cell::OnceCell;
+    use std::fs;
+    use std::path::Path;
+
+    use id3::TagLike;
     use lofty::AudioFile;
     use lofty::ParseOptions;
 
+    use crate::config::Config;
+    use crate::transcode::ArchiveMode;
+    use crate::transcode::AudioProperties;
     use crate::transcode::File;
+    use crate::transcode::FileType;
+    use crate::transcode::SourceDir;
+    use crate::transcode::TagField;
+    use crate::transcode::Target;
+    use crate::transcode::TranscodeAction;
+    use crate::transcode::TranscodePolicy;
 
     fn test_duration() {
         let infile = "foo.flac";
-        let outfile = "foo.flac.mp3";
+        let outfile = "foo.mp3";
 
-        File::new(infile).unwrap().transcode().unwrap();
+        File::new(infile)
+            .unwrap()
+            .transcode(&TranscodePolicy::default(), ".")
+            .unwrap();
 
         let mut buf = std::fs::File::open(infile).unwrap();
         let flacfile = lofty::flac::FlacFile::read_from(&mut buf, ParseOptions::default()).unwrap();
@@ -460,4 +1433,327 @@ mod tests {
 
         assert_eq!(flacdur, mp3dur);
     }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = TranscodePolicy::default();
+        assert_eq!(
+            policy.decide(FileType::FLAC, || None),
+            TranscodeAction::Transcode(Target::Mp3Vbr(0))
+        );
+        assert_eq!(
+            policy.decide(FileType::MP3, || Some(320)),
+            TranscodeAction::Skip
+        );
+    }
+
+    #[test]
+    fn test_policy_from_config() {
+        let cfg = Config::parse(
+            r#"
+[transcode]
+
+[[transcode.rules]]
+input = "MP3"
+min_bitrate = 320
+action = { transcode = { mp3_vbr = 0 } }
+
+[[transcode.rules]]
+input = "MP3"
+action = "copy"
+"#,
+        )
+        .unwrap();
+        let policy = cfg.transcode;
+
+        // lossy to lossy is refused by default, even if a rule asks for it
+        assert_eq!(
+            policy.decide(FileType::MP3, || Some(320)),
+            TranscodeAction::Skip
+        );
+        assert_eq!(
+            policy.decide(FileType::MP3, || Some(192)),
+            TranscodeAction::Copy
+        );
+        // no rule for flac
+        assert_eq!(
+            policy.decide(FileType::FLAC, || None),
+            TranscodeAction::Skip
+        );
+
+        let policy = policy
+            .with_lossy_to_lossy(true)
+            .with_target(Target::Opus(160));
+        assert_eq!(
+            policy.decide(FileType::MP3, || Some(320)),
+            TranscodeAction::Transcode(Target::Opus(160))
+        );
+        // unknown bitrate only matches unbounded rules
+        assert_eq!(policy.decide(FileType::MP3, || None), TranscodeAction::Copy);
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!("mp3-v0".parse::<Target>().unwrap(), Target::Mp3Vbr(0));
+        assert_eq!("MP3-320".parse::<Target>().unwrap(), Target::Mp3Cbr(320));
+        assert_eq!("opus-160".parse::<Target>().unwrap(), Target::Opus(160));
+        assert!("flac-8".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn test_file_new_non_audio() {
+        let dir = std::env::temp_dir().join("coggers_test_file_new");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+        fs::write(&path, "not audio").unwrap();
+        let f = File::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(f.file_type, FileType::Unknown);
+        assert!(f.properties.get().is_none()); // not read yet
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_properties_display() {
+        let props = AudioProperties {
+            duration: 225_400,
+            codec: "FLAC".to_string(),
+            sample_rate: Some(44100),
+            bit_depth: Some(16),
+            channels: Some(2),
+            bitrate: Some(912),
+        };
+        assert_eq!(
+            props.to_string(),
+            "FLAC, 44100 Hz, 16 bit, 2 ch, 912 kbps, 3:45"
+        );
+    }
+
+    #[test]
+    fn test_archive() {
+        let root = std::env::temp_dir().join("coggers_test_archive");
+        let _ = fs::remove_dir_all(&root);
+        let src = root.join("source");
+        let archive = root.join("archive");
+        fs::create_dir_all(src.join("foo")).unwrap();
+
+        let file = src.join("foo/1.flac");
+        fs::write(&file, "").unwrap();
+        ArchiveMode::Hardlink(archive.clone())
+            .archive(&file, &src)
+            .unwrap();
+        assert!(file.exists());
+        assert!(archive.join("foo/1.flac").exists());
+
+        // refuse to overwrite an existing archived file
+        assert!(ArchiveMode::Move(archive.clone())
+            .archive(&file, &src)
+            .is_err());
+
+        let file = src.join("foo/2.flac");
+        fs::write(&file, "").unwrap();
+        format!("move:{}", archive.display())
+            .parse::<ArchiveMode>()
+            .unwrap()
+            .archive(&file, &src)
+            .unwrap();
+        assert!(!file.exists());
+        assert!(archive.join("foo/2.flac").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_output_path() {
+        let root = std::env::temp_dir().join("coggers_test_output_path");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("foo")).unwrap();
+
+        let path = root.join("foo/1.mp3");
+        fs::write(&path, "").unwrap();
+        let f = File {
+            path: path.to_string_lossy().to_string(),
+            file_type: FileType::MP3,
+            tags: id3::Tag::new(),
+            properties: OnceCell::new(),
+        };
+        let root_str = root.to_str().unwrap();
+
+        // the original may be replaced, unless it is kept
+        let policy = TranscodePolicy::default();
+        assert_eq!(
+            f.output_path("mp3", &policy, root_str).unwrap(),
+            Some(path.clone())
+        );
+        assert_eq!(
+            f.output_path("opus", &policy, root_str).unwrap(),
+            Some(root.join("foo/1.opus"))
+        );
+        let policy = policy.with_archive(ArchiveMode::Keep);
+        assert_eq!(f.output_path("mp3", &policy, root_str).unwrap(), None);
+
+        let policy = policy.with_output_root(root.join("out"));
+        assert_eq!(
+            f.output_path("mp3", &policy, root_str).unwrap(),
+            Some(root.join("out/foo/1.mp3"))
+        );
+        assert!(root.join("out/foo").is_dir());
+
+        // already transcoded in a previous run
+        fs::write(root.join("out/foo/1.mp3"), "").unwrap();
+        assert_eq!(f.output_path("mp3", &policy, root_str).unwrap(), None);
+
+        // the source directory is left alone by default
+        let policy = TranscodePolicy::default().with_output_root(root.join("out"));
+        assert_eq!(policy.archive_mode(), ArchiveMode::Keep);
+        assert!(policy.validate().is_ok());
+        assert!(policy.with_archive(ArchiveMode::Delete).validate().is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_split_cues_rerun() {
+        let root = std::env::temp_dir().join("coggers_test_split_cues");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/foo")).unwrap();
+        fs::create_dir_all(root.join("archive/foo")).unwrap();
+
+        let cue = r#"FILE "image.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "A"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "B"
+    INDEX 01 03:00:00
+"#;
+        fs::write(root.join("src/foo/image.cue"), cue).unwrap();
+        // as left behind by a previous run with `ArchiveMode::Hardlink`
+        fs::write(root.join("archive/foo/image.cue"), cue).unwrap();
+        for f in ["image.flac", "01 - A.flac", "02 - B.flac"] {
+            fs::write(root.join("src/foo").join(f), "").unwrap();
+        }
+
+        let dir = SourceDir::new(root.join("src").to_str().unwrap()).unwrap();
+        let policy =
+            TranscodePolicy::default().with_archive(ArchiveMode::Hardlink(root.join("archive")));
+        assert!(dir.split_cues(&policy).is_ok());
+        assert!(root.join("src/foo/image.cue").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_multi_disc_files() {
+        let root = std::env::temp_dir().join("coggers_test_multi_disc");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("CD1")).unwrap();
+        fs::create_dir_all(root.join("CD2")).unwrap();
+
+        // header only; enough to infer the file type
+        let mut wav = vec![];
+        wav.extend(b"RIFF");
+        wav.extend(36_u32.to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.resize(44, 0);
+        fs::write(root.join("CD2/01.wav"), &wav).unwrap();
+        fs::write(root.join("CD1/02.wav"), &wav).unwrap();
+        fs::write(root.join("CD1/01.wav"), &wav).unwrap();
+        fs::write(root.join("cover.jpg"), [0xFF, 0xD8, 0xFF, 0xE0]).unwrap();
+        fs::write(root.join("empty.log"), b"").unwrap();
+
+        let dir = SourceDir::new(root.to_str().unwrap()).unwrap();
+        let files: Vec<String> = dir
+            .files()
+            .into_iter()
+            .map(|f| {
+                Path::new(&f.path)
+                    .strip_prefix(&root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(files, ["CD1/01.wav", "CD1/02.wav", "CD2/01.wav"]);
+
+        // loudness is grouped by the direct child, not the disc
+        let albums: Vec<_> = dir.files().iter().map(|f| dir.album_dir(&f.path)).collect();
+        assert_eq!(
+            albums,
+            [root.join("CD1"), root.join("CD1"), root.join("CD2")]
+        );
+        let parent = SourceDir::new(std::env::temp_dir().to_str().unwrap()).unwrap();
+        assert!(dir
+            .files()
+            .iter()
+            .all(|f| parent.album_dir(&f.path) == root));
+        assert_eq!(
+            dir.album_dir(root.join("cover.jpg").to_str().unwrap()),
+            root
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_decoder() {
+        let decoder = |file_type| {
+            let f = File {
+                path: "foo".to_string(),
+                file_type,
+                tags: id3::Tag::new(),
+                properties: OnceCell::new(),
+            };
+            f.decoder().get_program().to_string_lossy().to_string()
+        };
+        assert_eq!(decoder(FileType::FLAC), "flac");
+        assert_eq!(decoder(FileType::MP3), "lame");
+        assert_eq!(decoder(FileType::OPUS), "ffmpeg");
+    }
+
+    #[test]
+    fn test_opus_tag_args() {
+        let mut f = File {
+            path: "foo.flac".to_string(),
+            file_type: FileType::FLAC,
+            tags: id3::Tag::new(),
+            properties: OnceCell::new(),
+        };
+        f.set(TagField::Title, "So What");
+        f.tags.add_frame(id3::frame::ExtendedText {
+            description: "BARCODE".to_string(),
+            value: "074646493524".to_string(),
+        });
+        let args = f.opus_tag_args();
+        assert!(args.windows(2).any(|a| a == ["--title", "So What"]));
+        assert!(args
+            .windows(2)
+            .any(|a| a == ["--comment", "BARCODE=074646493524"]));
+        assert_eq!(f.extended("BARCODE"), Some("074646493524"));
+        assert_eq!(f.extended("CATALOGNUMBER"), None);
+    }
+
+    #[test]
+    fn test_validate_tag_field() {
+        assert!(TagField::Year.validate("1984").is_ok());
+        assert!(TagField::Year.validate("198x").is_err());
+        assert!(TagField::TrackNumber.validate("-1").is_err());
+        assert!(TagField::DiscogsReleaseId.validate("x").is_err());
+        assert!(TagField::Title.validate("").is_ok());
+
+        // an empty value clears the field
+        assert!(TagField::Year.validate("").is_ok());
+        assert!(TagField::DiscogsReleaseId.validate("").is_ok());
+        let mut f = File {
+            path: "foo.mp3".to_string(),
+            file_type: FileType::MP3,
+            tags: id3::Tag::new(),
+            properties: OnceCell::new(),
+        };
+        f.set(TagField::Year, "1984");
+        f.set(TagField::DiscogsReleaseId, "123");
+        f.set(TagField::Year, "");
+        f.set(TagField::DiscogsReleaseId, "");
+        assert_eq!(f.get(TagField::Year), None);
+        assert_eq!(f.get(TagField::DiscogsReleaseId), None);
+    }
 } //}}}
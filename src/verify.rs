@@ -0,0 +1,273 @@
+//! Audio integrity checks. Every file is decoded in full, and the results are
+//! stored in sqlite, so that files which have broken since the last run can be
+//! reported.
+//!
+//! - FLAC: `flac --test`, which checks frame CRCs and the MD5 of the decoded
+//!   audio
+//! - Everything else: ffmpeg, with CRC/bitstream checks enabled. A decoded
+//!   duration shorter than the one announced in the header (e.g. by the Xing
+//!   frame of a VBR MP3) indicates a truncated stream.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::Result;
+use lofty::AudioFile;
+use rusqlite::named_params;
+use rusqlite::Connection;
+use walkdir::DirEntry;
+
+use crate::db;
+use crate::io::Walk;
+
+const AUDIO_EXTENSIONS: [&str; 6] = ["flac", "mp3", "wav", "opus", "ogg", "m4a"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyStatus {
+    Ok,
+    /// Decoding produced errors
+    Corrupt,
+    /// Decoded cleanly, but ended early
+    Truncated,
+    /// Could not be opened or decoded at all
+    Unreadable,
+}
+
+impl VerifyStatus {
+    fn as_str(&self) -> &str {
+        match self {
+            VerifyStatus::Ok => "ok",
+            VerifyStatus::Corrupt => "corrupt",
+            VerifyStatus::Truncated => "truncated",
+            VerifyStatus::Unreadable => "unreadable",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "ok" => VerifyStatus::Ok,
+            "corrupt" => VerifyStatus::Corrupt,
+            "truncated" => VerifyStatus::Truncated,
+            _ => VerifyStatus::Unreadable,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifyResult {
+    pub path: String,
+    pub status: VerifyStatus,
+    pub error: Option<String>,
+}
+
+impl Display for VerifyResult {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.status.as_str(), self.path)?;
+        if let Some(err) = &self.error {
+            // ffmpeg tends to repeat the same error for every frame
+            write!(f, ": {}", err.lines().next().unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+}
+
+/// Decode the file in its entirety; this is slow (roughly the speed of a
+/// transcode).
+pub fn verify_file(path: &str) -> VerifyResult {
+    let result = |status, error: Option<String>| VerifyResult {
+        path: path.to_string(),
+        status,
+        error,
+    };
+
+    let is_flac = Path::new(path)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("flac"));
+
+    let out = match is_flac {
+        true => Command::new("flac")
+            .args(["--test", "--silent", path])
+            .stdin(Stdio::null())
+            .output(),
+        false => Command::new("ffmpeg")
+            .args(["-v", "error", "-err_detect", "crccheck+bitstream+buffer"])
+            .args(["-i", path, "-f", "null", "-progress", "pipe:1", "-"])
+            .stdin(Stdio::null())
+            .output(),
+    };
+    let out = match out {
+        Ok(out) => out,
+        Err(e) => return result(VerifyStatus::Unreadable, Some(e.to_string())),
+    };
+
+    let stderr = String::from_utf8_lossy(&out.stderr).trim().to_string();
+    if !out.status.success() {
+        return result(VerifyStatus::Unreadable, Some(stderr));
+    }
+    if !stderr.is_empty() {
+        return result(VerifyStatus::Corrupt, Some(stderr));
+    }
+
+    if !is_flac {
+        // `out_time_us` is reported periodically; the last value is the total
+        let decoded = String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .rev()
+            .find_map(|l| l.strip_prefix("out_time_us=")?.parse::<u64>().ok())
+            .unwrap_or(0)
+            / 1000;
+        let expected = lofty::read_from_path(path)
+            .map(|f| f.properties().duration().as_millis() as u64)
+            .unwrap_or(0);
+        if decoded + 1000 < expected {
+            return result(
+                VerifyStatus::Truncated,
+                Some(format!("decoded {decoded} ms of {expected} ms")),
+            );
+        }
+    }
+
+    result(VerifyStatus::Ok, None)
+}
+
+/// Schema history of the verification database; see `db::migrate`.
+const VERIFY_MIGRATIONS: [&str; 1] = [
+    // databases created before versioning already have this table
+    "create table if not exists files (
+        path text primary key,
+        status text not null,
+        error text,
+        checked_at integer not null
+    );",
+];
+
+/// Results of the most recent check of each file, keyed by path.
+pub struct VerifyDB {
+    db_path: String,
+}
+
+impl VerifyDB {
+    pub fn new(db_path: &str) -> Self {
+        Self {
+            db_path: db_path.to_string(),
+        }
+    }
+
+    fn open(&self) -> rusqlite::Result<Connection> { db::open(&self.db_path, &VERIFY_MIGRATIONS) }
+
+    pub fn load(&self) -> rusqlite::Result<HashMap<String, VerifyStatus>> {
+        let conn = self.open()?;
+        let mut stmt = conn.prepare("select path, status from files")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                VerifyStatus::parse(&row.get::<_, String>(1)?),
+            ))
+        })?;
+        rows.collect()
+    }
+
+    pub fn store(
+        &self,
+        results: &[VerifyResult],
+    ) -> rusqlite::Result<()> {
+        let mut conn = self.open()?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO
+                files ( path,  status,  error,  checked_at)
+                values (:path, :status, :error, :checked_at)",
+            )?;
+            for r in results {
+                stmt.execute(named_params! {
+                    ":path":       r.path,
+                    ":status":     r.status.as_str(),
+                    ":error":      r.error,
+                    ":checked_at": now,
+                })?;
+            }
+        }
+        tx.commit()
+    }
+}
+
+/// Verify all audio files under `dirs`, store the results, and return the
+/// files that are broken now, but were either fine or not checked at all on the
+/// previous run.
+pub fn verify_all(
+    dirs: impl Iterator<Item = DirEntry>,
+    db: &VerifyDB,
+) -> Result<Vec<VerifyResult>> {
+    let previous = db.load()?;
+
+    let results: Vec<VerifyResult> = dirs
+        .flat_map(|d| d.walk().collect::<Vec<_>>())
+        .filter(|f| is_audio(f.path()))
+        .map(|f| verify_file(f.as_str()))
+        .collect();
+
+    db.store(&results)?;
+
+    Ok(results
+        .into_iter()
+        .filter(|r| r.status != VerifyStatus::Ok)
+        .filter(|r| matches!(previous.get(&r.path), None | Some(VerifyStatus::Ok)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::verify::is_audio;
+    use crate::verify::VerifyDB;
+    use crate::verify::VerifyResult;
+    use crate::verify::VerifyStatus;
+
+    #[test]
+    fn test_is_audio() {
+        assert!(is_audio(Path::new("foo/01 bar.FLAC")));
+        assert!(is_audio(Path::new("foo/01 bar.mp3")));
+        assert!(!is_audio(Path::new("foo/cover.jpg")));
+        assert!(!is_audio(Path::new("foo/mp3")));
+    }
+
+    #[test]
+    fn test_store() {
+        let path = std::env::temp_dir().join("coggers_test_verify.db");
+        let _ = std::fs::remove_file(&path);
+        let db = VerifyDB::new(path.to_str().unwrap());
+
+        let result = |status| VerifyResult {
+            path: "foo.mp3".to_string(),
+            status,
+            error: None,
+        };
+        db.store(&[result(VerifyStatus::Ok)]).unwrap();
+        db.store(&[result(VerifyStatus::Truncated)]).unwrap();
+
+        let prev = db.load().unwrap();
+        assert_eq!(prev.len(), 1);
+        assert_eq!(prev.get("foo.mp3"), Some(&VerifyStatus::Truncated));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
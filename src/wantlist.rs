@@ -0,0 +1,236 @@
+//! The wantlist, i.e. releases we would like to buy. Unlike the collection, a
+//! release can only be wanted once, so the release ID is enough to identify an
+//! entry.
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+use reqwest::Method;
+use rusqlite::named_params;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::collection::CollectionRelease;
+use crate::collection::CollectionResult;
+use crate::db;
+use crate::http;
+use crate::reconcile::Discrepancy;
+use crate::reconcile::Report;
+use crate::release::Release;
+use crate::search::Page;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Want {
+    /// Release ID
+    pub id: usize,
+    pub rating: u8,
+    #[serde(default)]
+    pub notes: String,
+    pub date_added: String,
+    pub basic_information: CollectionRelease,
+}
+
+#[derive(Deserialize, Debug)]
+struct WantsPage {
+    pagination: Page,
+    wants: Vec<Want>,
+}
+
+/// Schema history of the wantlist database; see `db::migrate`.
+const WANTLIST_MIGRATIONS: [&str; 1] = ["create table wants (
+        release_id integer primary key,
+        master_id integer not null,
+        artist text not null,
+        title text not null,
+        year integer not null,
+        rating integer not null,
+        notes text not null,
+        date_added text not null
+    );
+    create index wants_master_id on wants (master_id);"];
+
+#[derive(Debug, Default)]
+pub struct Wantlist {
+    pub wants: Vec<Want>,
+}
+
+impl Wantlist {
+    pub fn dump() -> Result<Self> {
+        let mut wants = vec![];
+        let mut page = 1;
+        loop {
+            let url = format!("wants?per_page=100&page={page}");
+            let resp =
+                http::make_request(http::RequestType::Collection, &url)?.error_for_status()?;
+            let mut p: WantsPage = serde_json::from_str(&resp.text()?)?;
+            wants.append(&mut p.wants);
+            if p.pagination.page >= p.pagination.pages {
+                break;
+            }
+            page += 1;
+        }
+        Ok(Self { wants })
+    }
+
+    /// Adding a release that is already wanted only updates its notes.
+    pub fn add(
+        release_id: usize,
+        notes: Option<&str>,
+    ) -> Result<()> {
+        let body = notes.map(|n| json!({ "notes": n }));
+        http::send(
+            Method::PUT,
+            http::RequestType::Collection,
+            &format!("wants/{release_id}"),
+            body.as_ref(),
+        )?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    pub fn remove(release_id: usize) -> Result<()> {
+        http::send(
+            Method::DELETE,
+            http::RequestType::Collection,
+            &format!("wants/{release_id}"),
+            None,
+        )?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Replace the contents of the database at `db_path`
+    pub fn to_sql(
+        &self,
+        db_path: &str,
+    ) -> Result<()> {
+        let mut conn = db::open(db_path, &WANTLIST_MIGRATIONS)?;
+        let tx = conn.transaction()?;
+        tx.execute("delete from wants", [])?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO
+                wants ( release_id,  master_id,  artist,  title,  year,  rating,  notes,  date_added)
+                values (:release_id, :master_id, :artist, :title, :year, :rating, :notes, :date_added)",
+            )?;
+            for w in self.wants.iter() {
+                let info = &w.basic_information;
+                stmt.execute(named_params! {
+                    ":release_id": w.id,
+                    ":master_id":  info.master_id,
+                    ":artist":     info.artists.first().map(|a| a.name.as_str()).unwrap_or_default(),
+                    ":title":      info.title,
+                    ":year":       info.year,
+                    ":rating":     w.rating,
+                    ":notes":      w.notes,
+                    ":date_added": w.date_added,
+                })?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Masters that are neither owned (in any release) nor already wanted
+    fn is_missing(
+        &self,
+        master_id: usize,
+        collection: &[CollectionResult],
+    ) -> bool {
+        master_id != 0
+            && !collection
+                .iter()
+                .any(|r| r.basic_information.master_id == master_id)
+            && !self
+                .wants
+                .iter()
+                .any(|w| w.basic_information.master_id == master_id)
+    }
+
+    /// For every library album that is not in the collection (see
+    /// `reconcile`), want the main release of its master, unless some release
+    /// of that master is already owned or wanted. Untagged albums are searched
+    /// for, so this can take a while; results whose artist or title are not
+    /// similar (see `SearchRelease::matches`) are never used. Each release is
+    /// printed as it is added (or would be, if `dry_run`). Returns the release
+    /// IDs added.
+    pub fn add_missing_masters(
+        &self,
+        report: &Report,
+        collection: &[CollectionResult],
+        notes: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Vec<usize>> {
+        let mut added = vec![];
+        let mut seen = HashSet::new();
+        for row in report
+            .rows
+            .iter()
+            .filter(|r| r.kind == Discrepancy::RippedNotOwned)
+        {
+            let master_id = match row.release_id {
+                Some(id) => Release::get(id).map(|r| r.master_id),
+                None => Release::search(&row.artist, &row.title)?
+                    .results
+                    .iter()
+                    .find(|r| r.matches(&row.artist, &row.title))
+                    .map(|r| r.master_id),
+            }
+            .unwrap_or(0);
+            if !seen.insert(master_id) || !self.is_missing(master_id, collection) {
+                continue;
+            }
+            let Some(master) = Release::get_master(master_id) else {
+                continue;
+            };
+            println!("{}\t{} - {}", master.main_release, row.artist, row.title);
+            if !dry_run {
+                Self::add(master.main_release, notes)?;
+            }
+            added.push(master.main_release);
+        }
+        Ok(added)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collection::test_instance;
+    use crate::wantlist::Wantlist;
+    use crate::wantlist::WantsPage;
+
+    const PAGE: &str = r#"{
+        "pagination": {"items": 1, "page": 1, "pages": 1, "per_page": 100},
+        "wants": [{
+            "id": 1, "rating": 0, "date_added": "2024-01-01T00:00:00-08:00",
+            "resource_url": "",
+            "basic_information": {
+                "id": 1, "master_id": 10, "artists": [], "genres": [], "labels": [],
+                "master_url": null, "resource_url": "", "styles": [], "title": "Foo",
+                "year": 1990
+            }
+        }]
+    }"#;
+
+    #[test]
+    fn test_wantlist() {
+        let page: WantsPage = serde_json::from_str(PAGE).unwrap();
+        assert_eq!(page.pagination.pages, 1);
+        assert_eq!(page.wants[0].notes, "");
+
+        let wantlist = Wantlist { wants: page.wants };
+        assert!(!wantlist.is_missing(10, &[])); // already wanted
+        assert!(!wantlist.is_missing(0, &[])); // no master
+        assert!(wantlist.is_missing(11, &[]));
+        let mut owned = test_instance(1, 2, "");
+        owned.basic_information.master_id = 11;
+        assert!(!wantlist.is_missing(11, &[owned])); // already owned
+
+        let path = std::env::temp_dir().join("coggers_test_wantlist.db");
+        let _ = std::fs::remove_file(&path);
+        wantlist.to_sql(path.to_str().unwrap()).unwrap();
+        wantlist.to_sql(path.to_str().unwrap()).unwrap(); // replaced, not duplicated
+        std::fs::remove_file(&path).unwrap();
+    }
+}